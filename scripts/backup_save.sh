@@ -2,14 +2,18 @@
 
 current_time=$(date +"%Y%m%d%H%M%S")
 source_dir="/home/ubuntu/Steam/steamapps/common/PalServer/Pal/Saved"
-target_dir="/home/ubuntu/psm/saves"
+target_dir="/home/ubuntu/psm/saves/$1"
 
 cd /tmp/ && rm -rf ./Saved && cp -r $source_dir ./
 
 name="Saved.$current_time.tar.gz"
 
-tar -czvf $name Saved
+tar -czf $name Saved || exit 1
 
+mkdir -p $target_dir
 cp $name $target_dir
 
-echo $name
+checksum=$(sha256sum $name | cut -d ' ' -f 1)
+
+# last stdout line is the result read by psm
+echo "{\"save\": \"$name\", \"checksum\": \"$checksum\"}"
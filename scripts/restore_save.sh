@@ -1,11 +1,23 @@
 #!/bin/bash
 
 # source_dir="/home/ubuntu/Steam/steamapps/common/PalServer/Pal/Saved"
-dir="/home/ubuntu/psm/saves"
+dir="/home/ubuntu/psm/saves/$1"
+name=$2
+checksum=$3
 
-find $dir -type f | grep "tar.gz" | sort -r | head -n 1 | xargs -I {} cp {} /tmp/
+if [ ! -f "$dir/$name" ]; then
+    echo "save $name not found" >&2
+    exit 1
+fi
+
+if ! echo "$checksum  $dir/$name" | sha256sum -c --status; then
+    echo "checksum mismatch of $name" >&2
+    exit 1
+fi
+
+cp "$dir/$name" /tmp/
 cd /tmp/ && rm -rf ./Saved
-tar -zxvf Saved.*.tar.gz
+tar -zxf "$name" || exit 1
 rm -rf /home/ubuntu/Steam/steamapps/common/PalServer/Pal/Saved
 cp -r ./Saved /home/ubuntu/Steam/steamapps/common/PalServer/Pal/Saved
-rm -rf Saved.*.tar.gz
+rm -rf "$name"
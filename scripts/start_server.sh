@@ -12,6 +12,6 @@ if [ $? -eq 0 ]; then
     sleep 2
     echo "Server started"
 else
-    echo "failed"
-    exist -1
+    echo "failed" >&2
+    exit 1
 fi
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Debug, Parser)]
 pub struct BotCmd {
@@ -9,27 +9,7 @@ pub struct BotCmd {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// manager server
-    Server {
-        // #[clap(short, long)]
-        // list: bool,
-        /// query Server status
-        #[clap(long, value_name = "Save Name")]
-        status: Option<String>,
-
-        // #[clap(short, long, value_name = "Save Name")]
-        // new: Option<String>,
-        /// start Server with Save
-        #[clap(long, value_name = "Save Name")]
-        start: Option<String>,
-
-        /// stop Server with Save
-        #[clap(long, value_name = "Save Name")]
-        stop: Option<String>,
-
-        /// backup current file while running
-        #[clap(long, value_name = "Save Name")]
-        save: Option<String>,
-    },
+    Server(ServerArgs),
     Config {
         r#type: String,
     },
@@ -41,3 +21,42 @@ pub enum Commands {
     //     query: Option<String>
     // }
 }
+
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    // #[clap(short, long)]
+    // list: bool,
+    /// query Server status
+    #[clap(long, value_name = "Save Name")]
+    pub status: Option<String>,
+
+    // #[clap(short, long, value_name = "Save Name")]
+    // new: Option<String>,
+    /// start Server with Save
+    #[clap(long, value_name = "Save Name")]
+    pub start: Option<String>,
+
+    /// only look for instances in this region when starting
+    #[clap(long, value_name = "Region", requires = "start")]
+    pub region: Option<String>,
+
+    /// restore this archive instead of the latest save when starting
+    #[clap(long, value_name = "Archive", requires = "start")]
+    pub from: Option<String>,
+
+    /// stop Server with Save
+    #[clap(long, value_name = "Save Name")]
+    pub stop: Option<String>,
+
+    /// backup current file while running
+    #[clap(long, value_name = "Save Name")]
+    pub save: Option<String>,
+
+    /// list archives of Save
+    #[clap(long, value_name = "Save Name")]
+    pub saves: Option<String>,
+
+    /// abort starting Server, its instance is terminated
+    #[clap(long, value_name = "Save Name")]
+    pub cancel: Option<String>,
+}
@@ -1,31 +1,218 @@
 use anyhow::Context;
 use cqhttp_bot_frame::bot::BotConfig;
-use serde::Deserialize;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, path::Path};
 use tencentcloud_sdk::config::ClientConfig;
 
-use crate::local_storage::LocalSaveStorageConfig;
+use crate::{
+    csp::{
+        aws::AwsProviderConfig, docker::DockerProviderConfig, hetzner::HetznerProviderConfig,
+        mock::MockProviderConfig, CloudProviders,
+    },
+    local_storage::{
+        LocalSaveStorageConfig, LocalStorage, S3SaveStorageConfig, WebdavSaveStorageConfig,
+    },
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PsmConfig {
-    pub csp: CSPConfig,
+    pub csp: CSPConfigs,
+    #[serde(default)]
+    pub placement: PlacementConfig,
+    #[serde(default)]
+    pub backup: BackupConfig,
     pub bot: Option<BotConfig>,
-    pub storage: SaveStorageConfig,
+    /// where background tasks report when no bot message is there to reply to
+    #[serde(default)]
+    pub notify: Option<NotifyConfig>,
+    pub storage: SaveStorageConfigs,
     pub ssh: SshConfig,
     pub nps: NpsAccessConfig,
     pub whitelist: WhiteListConfig,
 }
 
+/// a OneBot group messaged through the http api of the bot implementation
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotifyConfig {
+    /// e.g. `http://127.0.0.1:5700`
+    pub http_api: String,
+    pub group_id: i64,
+    #[serde(default)]
+    pub access_token: Option<String>,
+}
+
+/// a single provider, or a list of them to compare prices across
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum CSPConfigs {
+    Single(CSPConfig),
+    Multiple(Vec<ProviderConfig>),
+}
+
+impl CSPConfigs {
+    pub fn providers(&self) -> Vec<ProviderConfig> {
+        match self {
+            CSPConfigs::Single(csp) => vec![ProviderConfig {
+                name: None,
+                currency_rate: default_currency_rate(),
+                penalty: 0.0,
+                csp: csp.clone(),
+            }],
+            CSPConfigs::Multiple(providers) => providers.clone(),
+        }
+    }
+
+    /// servers record the provider by name, so two with the same one would mix up accounts
+    pub fn check_names(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+        for provider in self.providers() {
+            let name = provider.name();
+            anyhow::ensure!(
+                names.insert(name.clone()),
+                "provider name {name} is used twice, give each provider its own name"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProviderConfig {
+    /// recorded on running servers, defaults to the provider kind, e.g. `tencent_cloud`
+    #[serde(default)]
+    pub name: Option<String>,
+    /// converts this provider's prices into the common currency, e.g. 0.14 for CNY when ranking in USD
+    #[serde(default = "default_currency_rate")]
+    pub currency_rate: f64,
+    /// added to the converted hourly price when ranking, raise it for far or less preferred providers
+    #[serde(default)]
+    pub penalty: f64,
+    #[serde(flatten)]
+    pub csp: CSPConfig,
+}
+
+fn default_currency_rate() -> f64 {
+    1.0
+}
+
+impl ProviderConfig {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.csp.kind().to_string())
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum CSPConfig {
     TencentCloud(ClientConfig),
+    /// aws ec2 spot instances
+    Aws(AwsProviderConfig),
+    /// hetzner cloud on-demand servers, billed hourly
+    Hetzner(HetznerProviderConfig),
+    /// local docker/podman container running sshd
+    Docker(DockerProviderConfig),
+    /// in-process fake provider for testing start/stop flows
+    Mock(MockProviderConfig),
+}
+
+impl CSPConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CSPConfig::TencentCloud(_) => "tencent_cloud",
+            CSPConfig::Aws(_) => "aws",
+            CSPConfig::Hetzner(_) => "hetzner",
+            CSPConfig::Docker(_) => "docker",
+            CSPConfig::Mock(_) => "mock",
+        }
+    }
+}
+
+/// where to look for instances, servers can override it in server status file
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PlacementConfig {
+    /// candidate regions, empty for each provider's default regions
+    #[serde(default)]
+    pub regions: Vec<String>,
+    #[serde(default)]
+    pub excluded_zones: Vec<String>,
+    /// hourly price ceiling in the common currency, more expensive offers are never launched
+    #[serde(default)]
+    pub max_price: Option<f64>,
+}
+
+/// periodic backups of running servers, servers can override it in server status file
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct BackupConfig {
+    /// minutes between backups, no periodic backup if unset
+    #[serde(default)]
+    pub interval: Option<u64>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// local hours `[start, end)` without backups, may wrap midnight like 23 ~ 7
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// a single save backend, or replicas of which the first is the primary
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SaveStorageConfigs {
+    Single(Box<SaveStorageConfig>),
+    Multiple(Vec<SaveStorageConfig>),
+}
+
+impl SaveStorageConfigs {
+    pub fn replicas(&self) -> Vec<SaveStorageConfig> {
+        match self {
+            SaveStorageConfigs::Single(storage) => vec![*storage.clone()],
+            SaveStorageConfigs::Multiple(storages) => storages.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum SaveStorageConfig {
     Local(LocalSaveStorageConfig),
+    /// s3 compatible bucket, tencent cos included
+    S3(S3SaveStorageConfig),
+    /// webdav share, e.g. on a nas
+    Webdav(WebdavSaveStorageConfig),
+}
+
+impl SaveStorageConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SaveStorageConfig::Local(_) => "local",
+            SaveStorageConfig::S3(_) => "s3",
+            SaveStorageConfig::Webdav(_) => "webdav",
+        }
+    }
+
+    /// local dirs every backend still keeps scripts in
+    pub fn local(&self) -> &LocalSaveStorageConfig {
+        match self {
+            SaveStorageConfig::Local(config) => config,
+            SaveStorageConfig::S3(config) => &config.local,
+            SaveStorageConfig::Webdav(config) => &config.local,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -36,6 +223,9 @@ pub struct SshConfig {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NpsAccessConfig {
+    /// provider name owning the nps instance, defaults to the first one
+    #[serde(default)]
+    pub provider: Option<String>,
     pub region: String,
     pub instance_id: String,
     pub protocol: String,
@@ -49,12 +239,25 @@ pub struct WhiteListConfig {
 }
 
 pub fn load_from_file(path: &Path) -> anyhow::Result<PsmConfig> {
-    config::Config::builder()
+    let config: PsmConfig = config::Config::builder()
         .add_source(config::File::from(path))
         .build()
         .with_context(|| format!("failed to load configuration from {}", path.display()))?
         .try_deserialize()
-        .context("failed to deserialize configuration")
+        .context("failed to deserialize configuration")?;
+    config
+        .csp
+        .check_names()
+        .context("invalid csp configuration")?;
+    CloudProviders::new(&config.csp.providers())
+        .check_regions(&config.placement.regions)
+        .context("invalid placement configuration")?;
+    anyhow::ensure!(
+        !config.storage.replicas().is_empty(),
+        "at least one storage is required"
+    );
+    LocalStorage::new(config.storage.replicas()).context("invalid storage configuration")?;
+    Ok(config)
 }
 
 pub fn default_config() -> String {
@@ -84,3 +287,21 @@ whitelist:
 "#
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_names_are_unique() {
+        let unnamed: CSPConfigs =
+            serde_yaml::from_str("- mock: { regions: {} }\n- mock: { regions: {} }\n").unwrap();
+        assert!(unnamed.check_names().is_err());
+
+        let named: CSPConfigs = serde_yaml::from_str(
+            "- name: a\n  mock: { regions: {} }\n- name: b\n  mock: { regions: {} }\n",
+        )
+        .unwrap();
+        assert!(named.check_names().is_ok());
+    }
+}
@@ -29,6 +29,19 @@ impl TryFrom<String> for ServiceInstanceType {
 }
 
 impl ServiceInstanceType {
+    /// (cpu cores, memory GB)
+    pub fn spec(&self) -> (u32, u32) {
+        match self {
+            ServiceInstanceType::T2C2G => (2, 2),
+            ServiceInstanceType::T2C8G => (2, 8),
+            ServiceInstanceType::T4C8G => (4, 8),
+            ServiceInstanceType::T2C16G => (2, 16),
+            ServiceInstanceType::T4C16G => (4, 16),
+            ServiceInstanceType::T4C32G => (4, 32),
+            ServiceInstanceType::T8C32G => (8, 32),
+        }
+    }
+
     pub fn to_list(&self) -> Vec<InstanceType> {
         match self {
             ServiceInstanceType::T2C2G => vec![InstanceType::SA2Medium2],
@@ -53,4 +66,30 @@ impl ServiceInstanceType {
             ServiceInstanceType::T8C32G => vec![InstanceType::SA22Xlarge32],
         }
     }
+
+    /// aws ec2 instance types with the same spec
+    pub fn to_aws_list(&self) -> Vec<&'static str> {
+        match self {
+            ServiceInstanceType::T2C2G => vec!["t3.small"],
+            ServiceInstanceType::T2C8G => vec!["m6a.large", "m5.large"],
+            ServiceInstanceType::T4C8G => vec!["c6a.xlarge", "c5.xlarge"],
+            ServiceInstanceType::T2C16G => vec!["r6a.large", "r5.large"],
+            ServiceInstanceType::T4C16G => vec!["m6a.xlarge", "m5.xlarge"],
+            ServiceInstanceType::T4C32G => vec!["r6a.xlarge", "r5.xlarge"],
+            ServiceInstanceType::T8C32G => vec!["m6a.2xlarge", "m5.2xlarge"],
+        }
+    }
+
+    /// hetzner cloud server types with the closest spec
+    pub fn to_hetzner_list(&self) -> Vec<&'static str> {
+        match self {
+            ServiceInstanceType::T2C2G => vec!["cx22"],
+            ServiceInstanceType::T2C8G => vec!["ccx13"],
+            ServiceInstanceType::T4C8G => vec!["cx32", "cpx31"],
+            ServiceInstanceType::T2C16G => vec!["ccx23"],
+            ServiceInstanceType::T4C16G => vec!["ccx23"],
+            ServiceInstanceType::T4C32G => vec!["ccx33"],
+            ServiceInstanceType::T8C32G => vec!["ccx33"],
+        }
+    }
 }
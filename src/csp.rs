@@ -0,0 +1,283 @@
+use std::{fmt::Display, future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    config::{CSPConfig, PlacementConfig, ProviderConfig},
+    constant::ServiceInstanceType,
+};
+
+pub(crate) mod aws;
+pub(crate) mod docker;
+pub(crate) mod hetzner;
+pub(crate) mod mock;
+pub(crate) mod tencent;
+
+/// a purchasable instance found by price query
+#[derive(Debug, Clone)]
+pub struct InstanceOffer {
+    pub region: String,
+    pub zone: String,
+    pub instance_type: String,
+    /// per hour
+    pub instance_price: f64,
+    /// per GB
+    pub bandwidth_price: f64,
+}
+
+impl Display for InstanceOffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} in {} with {}/h + {}/GB",
+            self.instance_type, self.region, self.instance_price, self.bandwidth_price
+        )
+    }
+}
+
+/// cheapest first, error if nothing available
+pub(crate) fn sort_offers(
+    mut offers: Vec<InstanceOffer>,
+    instance_types: impl std::fmt::Debug,
+) -> anyhow::Result<Vec<InstanceOffer>> {
+    if offers.is_empty() {
+        return Err(anyhow::anyhow!(
+            "failed to get any available instance of {instance_types:?}"
+        ));
+    }
+    offers.sort_by(|a, b| a.instance_price.total_cmp(&b.instance_price));
+    Ok(offers)
+}
+
+/// how long a new instance may take to run
+const BOOT_TIMEOUT: Duration = Duration::from_secs(62);
+
+/// call `poll` every `interval` until it gives the ip of the running `what`, error after `BOOT_TIMEOUT`
+pub(crate) async fn poll_instance_ip<F, Fut>(
+    what: &str,
+    interval: Duration,
+    mut poll: F,
+) -> anyhow::Result<String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<String>>>,
+{
+    let start_time = Instant::now();
+    loop {
+        if let Some(ip) = poll().await? {
+            return Ok(ip);
+        }
+        if Instant::now() - start_time >= BOOT_TIMEOUT {
+            return Err(anyhow::anyhow!("query {what} create status and ip timeout"));
+        }
+        sleep(interval).await;
+    }
+}
+
+/// everything psm needs from a cloud service provider
+#[async_trait]
+pub trait CloudProvider: Send + Sync {
+    /// regions to search when nothing else is specified
+    fn default_regions(&self) -> Vec<String>;
+
+    fn is_known_region(&self, region: &str) -> bool;
+
+    /// return all offers of `instance_type` in `candidate_regions` skipping `excluded_zones`, cheapest first
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>>;
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>>;
+
+    /// security groups prepared for palworld in `region`
+    async fn query_security_group_ids(&self, region: &str) -> anyhow::Result<Vec<String>>;
+
+    /// return instance id
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        key_ids: Vec<String>,
+        security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String>;
+
+    /// wait until instance running and return its public ip
+    async fn query_instance_ip(&self, region: &str, instance_id: &str) -> anyhow::Result<String>;
+
+    async fn terminate_instance(&self, region: &str, instance_id: &str) -> anyhow::Result<()>;
+
+    /// instance metadata url answering 200 once a spot instance is going to be reclaimed
+    fn termination_notice_url(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// IMDSv2 style token endpoint, the notice is then requested with the token
+    fn metadata_token_url(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// whether the provider reports the instance as being reclaimed, or it is already gone
+    async fn is_reclaiming(&self, _region: &str, _instance_id: &str) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// set `cidr_block` of firewall rules matching `protocol` and `port`
+    async fn modify_firewall_rules(
+        &self,
+        region: &str,
+        instance_id: &str,
+        protocol: &str,
+        port: &str,
+        cidr_block: &str,
+    ) -> anyhow::Result<()>;
+}
+
+/// an offer together with the provider selling it
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub provider: String,
+    pub offer: InstanceOffer,
+    /// hourly price in the common currency
+    pub price: f64,
+    /// price plus provider penalty, lower is better
+    pub score: f64,
+}
+
+impl Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} on {}", self.offer, self.provider)
+    }
+}
+
+struct NamedProvider {
+    name: String,
+    currency_rate: f64,
+    penalty: f64,
+    provider: Arc<dyn CloudProvider>,
+}
+
+/// all configured providers, the first one is the primary
+pub struct CloudProviders {
+    providers: Vec<NamedProvider>,
+}
+
+impl CloudProviders {
+    pub fn new(configs: &[ProviderConfig]) -> Self {
+        let providers = configs
+            .iter()
+            .map(|config| NamedProvider {
+                name: config.name(),
+                currency_rate: config.currency_rate,
+                penalty: config.penalty,
+                provider: build_cloud_provider(&config.csp),
+            })
+            .collect();
+        Self { providers }
+    }
+
+    /// provider by name, `None` is the primary one for servers created before multiple providers
+    pub fn get(&self, name: Option<&str>) -> anyhow::Result<Arc<dyn CloudProvider>> {
+        match name {
+            Some(name) => self.providers.iter().find(|p| p.name == name),
+            None => self.providers.first(),
+        }
+        .map(|p| p.provider.clone())
+        .ok_or(anyhow::anyhow!("cloud provider {name:?} not configured"))
+    }
+
+    pub fn is_known_region(&self, region: &str) -> bool {
+        self.providers
+            .iter()
+            .any(|p| p.provider.is_known_region(region))
+    }
+
+    /// error if any region is unknown to all providers
+    pub fn check_regions(&self, regions: &[String]) -> anyhow::Result<()> {
+        let unknown: Vec<_> = regions
+            .iter()
+            .filter(|r| !self.is_known_region(r))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!("unknown regions {unknown:?}"));
+        }
+        Ok(())
+    }
+
+    /// query offers of every provider in parallel, sorted by converted price plus penalty
+    pub async fn query_candidates(
+        &self,
+        instance_type: &ServiceInstanceType,
+        placement: &PlacementConfig,
+    ) -> anyhow::Result<Vec<Candidate>> {
+        let mut handles = vec![];
+        for p in &self.providers {
+            let provider = p.provider.clone();
+            let (name, currency_rate, penalty) = (p.name.clone(), p.currency_rate, p.penalty);
+            let instance_type = instance_type.clone();
+            let placement = placement.clone();
+            handles.push(tokio::spawn(async move {
+                let regions = if placement.regions.is_empty() {
+                    provider.default_regions()
+                } else {
+                    placement
+                        .regions
+                        .into_iter()
+                        .filter(|r| provider.is_known_region(r))
+                        .collect()
+                };
+                let offers = if regions.is_empty() {
+                    Err(anyhow::anyhow!("no candidate region"))
+                } else {
+                    provider
+                        .query_spot_paid_price(&regions, &placement.excluded_zones, &instance_type)
+                        .await
+                };
+                (offers, name, currency_rate, penalty)
+            }));
+        }
+
+        let mut candidates = vec![];
+        let mut errors = vec![];
+        for handle in handles {
+            match handle.await? {
+                (Ok(offers), provider, currency_rate, penalty) => {
+                    candidates.extend(offers.into_iter().map(|offer| {
+                        let price = offer.instance_price * currency_rate;
+                        Candidate {
+                            score: price + penalty,
+                            price,
+                            provider: provider.clone(),
+                            offer,
+                        }
+                    }))
+                }
+                (Err(e), provider, _, _) => errors.push(format!("{provider}: {e}")),
+            }
+        }
+        if candidates.is_empty() {
+            return Err(anyhow::anyhow!(
+                "failed to get any available instance, {}",
+                errors.join("; ")
+            ));
+        }
+        candidates.sort_by(|a, b| a.score.total_cmp(&b.score));
+        Ok(candidates)
+    }
+}
+
+fn build_cloud_provider(config: &CSPConfig) -> Arc<dyn CloudProvider> {
+    match config {
+        CSPConfig::TencentCloud(client_config) => {
+            Arc::new(tencent::TencentCloudProvider::new(client_config))
+        }
+        CSPConfig::Aws(aws_config) => Arc::new(aws::AwsProvider::new(aws_config)),
+        CSPConfig::Docker(docker_config) => Arc::new(docker::DockerProvider::new(docker_config)),
+        CSPConfig::Hetzner(hetzner_config) => {
+            Arc::new(hetzner::HetznerProvider::new(hetzner_config))
+        }
+        CSPConfig::Mock(mock_config) => Arc::new(mock::MockProvider::new(mock_config)),
+    }
+}
@@ -0,0 +1,491 @@
+use std::{collections::HashMap, time::Duration, time::SystemTime};
+
+use async_trait::async_trait;
+use aws_sdk_ec2::{
+    config::{BehaviorVersion, Credentials, Region},
+    error::{DisplayErrorContext, ProvideErrorMetadata},
+    primitives::DateTime,
+    types::{InstanceMarketOptionsRequest, InstanceStateName, InstanceType, MarketType, Placement},
+    Client,
+};
+use serde::Deserialize;
+
+use crate::constant::ServiceInstanceType;
+
+use super::{poll_instance_ip, sort_offers, CloudProvider, InstanceOffer};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AwsProviderConfig {
+    access_key_id: String,
+    secret_access_key: String,
+    /// region -> ubuntu AMI id, also the regions allowed to launch in
+    amis: HashMap<String, String>,
+    /// use only this key pair instead of the first one found
+    #[serde(default)]
+    key_name: Option<String>,
+    /// per GB, ec2 spot price history does not include traffic
+    #[serde(default = "default_bandwidth_price")]
+    bandwidth_price: f64,
+    /// custom endpoint, e.g. a local EC2-compatible stand-in
+    #[serde(default)]
+    endpoint: Option<String>,
+}
+
+fn default_bandwidth_price() -> f64 {
+    0.09
+}
+
+fn sdk_err(e: impl std::error::Error) -> anyhow::Error {
+    anyhow::anyhow!("{}", DisplayErrorContext(e))
+}
+
+pub struct AwsProvider {
+    config: AwsProviderConfig,
+}
+
+impl AwsProvider {
+    pub fn new(config: &AwsProviderConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    fn client(&self, region: &str) -> Client {
+        let mut builder = aws_sdk_ec2::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new(region.to_string()))
+            .credentials_provider(Credentials::new(
+                &self.config.access_key_id,
+                &self.config.secret_access_key,
+                None,
+                None,
+                "psm",
+            ));
+        if let Some(endpoint) = &self.config.endpoint {
+            builder = builder.endpoint_url(endpoint);
+        }
+        Client::from_conf(builder.build())
+    }
+
+    async fn query_region_price(
+        &self,
+        region: &str,
+        instance_types: &[&str],
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        let mut request = self
+            .client(region)
+            .describe_spot_price_history()
+            .product_descriptions("Linux/UNIX")
+            .start_time(DateTime::from(SystemTime::now()));
+        for instance_type in instance_types {
+            request = request.instance_types(InstanceType::from(*instance_type));
+        }
+        let resp = request.send().await.map_err(sdk_err)?;
+        Ok(resp
+            .spot_price_history()
+            .iter()
+            .filter_map(|p| {
+                Some(InstanceOffer {
+                    region: region.to_string(),
+                    zone: p.availability_zone()?.to_string(),
+                    instance_type: p.instance_type()?.as_str().to_string(),
+                    instance_price: p.spot_price()?.parse().ok()?,
+                    bandwidth_price: self.config.bandwidth_price,
+                })
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl CloudProvider for AwsProvider {
+    fn default_regions(&self) -> Vec<String> {
+        let mut regions: Vec<_> = self.config.amis.keys().cloned().collect();
+        regions.sort();
+        regions
+    }
+
+    fn is_known_region(&self, region: &str) -> bool {
+        self.config.amis.contains_key(region)
+    }
+
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        let candidate_instance_type = instance_type.to_aws_list();
+        let mut price_result = vec![];
+        for region in candidate_regions
+            .iter()
+            .filter(|r| self.config.amis.contains_key(*r))
+        {
+            price_result.extend(
+                self.query_region_price(region, &candidate_instance_type)
+                    .await?,
+            );
+        }
+        price_result.retain(|offer| !excluded_zones.contains(&offer.zone));
+        sort_offers(price_result, candidate_instance_type)
+    }
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>> {
+        // key pairs are regional, query them when launching
+        Ok(self.config.key_name.iter().cloned().collect())
+    }
+
+    async fn query_security_group_ids(&self, region: &str) -> anyhow::Result<Vec<String>> {
+        let resp = self
+            .client(region)
+            .describe_security_groups()
+            .send()
+            .await
+            .map_err(sdk_err)?;
+        Ok(resp
+            .security_groups()
+            .iter()
+            .filter(|sg| {
+                sg.group_name()
+                    .is_some_and(|name| name.to_ascii_lowercase().contains("palworld"))
+            })
+            .filter_map(|sg| sg.group_id().map(str::to_string))
+            .collect())
+    }
+
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        key_ids: Vec<String>,
+        security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String> {
+        let client = self.client(&offer.region);
+        let ami = self
+            .config
+            .amis
+            .get(&offer.region)
+            .ok_or(anyhow::anyhow!("no ami configured for {}", offer.region))?;
+        let key_name = match key_ids.into_iter().next() {
+            Some(key_name) => key_name,
+            None => client
+                .describe_key_pairs()
+                .send()
+                .await
+                .map_err(sdk_err)?
+                .key_pairs()
+                .iter()
+                .find_map(|k| k.key_name().map(str::to_string))
+                .ok_or(anyhow::anyhow!("no key pair in {}", offer.region))?,
+        };
+        let resp = client
+            .run_instances()
+            .image_id(ami)
+            .instance_type(InstanceType::from(offer.instance_type.as_str()))
+            .key_name(key_name)
+            .set_security_group_ids(Some(security_group_ids))
+            .placement(Placement::builder().availability_zone(&offer.zone).build())
+            .instance_market_options(
+                InstanceMarketOptionsRequest::builder()
+                    .market_type(MarketType::Spot)
+                    .build(),
+            )
+            .min_count(1)
+            .max_count(1)
+            .send()
+            .await
+            .map_err(sdk_err)?;
+        resp.instances()
+            .iter()
+            .find_map(|i| i.instance_id().map(str::to_string))
+            .ok_or(anyhow::anyhow!("run instances returns no instance"))
+    }
+
+    async fn query_instance_ip(&self, region: &str, instance_id: &str) -> anyhow::Result<String> {
+        let client = self.client(region);
+        poll_instance_ip("ec2", Duration::from_secs(5), || async {
+            let resp = client
+                .describe_instances()
+                .instance_ids(instance_id)
+                .send()
+                .await
+                .map_err(sdk_err)?;
+            resp.reservations()
+                .iter()
+                .flat_map(|r| r.instances())
+                .find(|i| {
+                    i.instance_id() == Some(instance_id)
+                        && i.state().and_then(|s| s.name()) == Some(&InstanceStateName::Running)
+                })
+                .map(|instance| {
+                    instance
+                        .public_ip_address()
+                        .map(str::to_string)
+                        .ok_or(anyhow::anyhow!("running ec2 without ip?"))
+                })
+                .transpose()
+        })
+        .await
+    }
+
+    async fn terminate_instance(&self, region: &str, instance_id: &str) -> anyhow::Result<()> {
+        self.client(region)
+            .terminate_instances()
+            .instance_ids(instance_id)
+            .send()
+            .await
+            .map_err(sdk_err)?;
+        Ok(())
+    }
+
+    async fn is_reclaiming(&self, region: &str, instance_id: &str) -> anyhow::Result<bool> {
+        let resp = match self
+            .client(region)
+            .describe_instances()
+            .instance_ids(instance_id)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) if e.code() == Some("InvalidInstanceID.NotFound") => return Ok(true),
+            Err(e) => return Err(sdk_err(e)),
+        };
+        Ok(resp
+            .reservations()
+            .iter()
+            .flat_map(|r| r.instances())
+            .find(|i| i.instance_id() == Some(instance_id))
+            .is_none_or(|i| {
+                matches!(
+                    i.state().and_then(|s| s.name()),
+                    Some(InstanceStateName::ShuttingDown | InstanceStateName::Terminated)
+                )
+            }))
+    }
+
+    fn termination_notice_url(&self) -> Option<&'static str> {
+        Some("http://169.254.169.254/latest/meta-data/spot/instance-action")
+    }
+
+    // current AMIs only answer IMDSv2 requests
+    fn metadata_token_url(&self) -> Option<&'static str> {
+        Some("http://169.254.169.254/latest/api/token")
+    }
+
+    async fn modify_firewall_rules(
+        &self,
+        _region: &str,
+        _instance_id: &str,
+        _protocol: &str,
+        _port: &str,
+        _cidr_block: &str,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("aws provider does not manage nps firewall"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use hyper::{Body, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::stand_in::serve;
+
+    const REGION: &str = "ap-east-1";
+    const IP: &str = "203.0.113.7";
+
+    /// an ec2 query api answering the actions psm sends, launched instances run right away
+    #[derive(Clone, Default)]
+    struct Ec2StandIn {
+        /// state of every launched instance by id
+        instances: Arc<Mutex<HashMap<String, &'static str>>>,
+        launched: Arc<AtomicUsize>,
+        /// parameters of every request, in order
+        requests: Arc<Mutex<Vec<HashMap<String, String>>>>,
+    }
+
+    impl Ec2StandIn {
+        fn serve(&self) -> String {
+            serve(self.clone(), Self::handle)
+        }
+
+        /// parameters of the first request doing `action`
+        fn request(&self, action: &str) -> HashMap<String, String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|params| params["Action"] == action)
+                .cloned()
+                .unwrap_or_else(|| panic!("no {action}"))
+        }
+
+        fn instance_state(state: &str) -> String {
+            let code = match state {
+                "pending" => 0,
+                "running" => 16,
+                "shutting-down" => 32,
+                _ => 48,
+            };
+            format!("<code>{code}</code><name>{state}</name>")
+        }
+
+        async fn handle(self, req: Request<Body>) -> Response<Body> {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let params: HashMap<String, String> =
+                reqwest::Url::parse(&format!("http://ec2/?{}", String::from_utf8_lossy(&body)))
+                    .unwrap()
+                    .query_pairs()
+                    .into_owned()
+                    .collect();
+            self.requests.lock().unwrap().push(params.clone());
+            let action = params["Action"].as_str();
+            let instance_id = params.get("InstanceId.1").cloned().unwrap_or_default();
+            let state = self.instances.lock().unwrap().get(&instance_id).copied();
+            let result = match (action, state) {
+                ("DescribeSpotPriceHistory", _) => {
+                    let items: String = (1..)
+                        .map_while(|n| params.get(&format!("InstanceType.{n}")))
+                        .map(|instance_type| {
+                            format!(
+                                "<item><instanceType>{instance_type}</instanceType><productDescription>Linux/UNIX</productDescription><spotPrice>0.012300</spotPrice><timestamp>2024-01-01T12:00:00.000Z</timestamp><availabilityZone>{REGION}a</availabilityZone></item>"
+                            )
+                        })
+                        .collect();
+                    Ok(format!("<spotPriceHistorySet>{items}</spotPriceHistorySet>"))
+                }
+                ("DescribeSecurityGroups", _) => Ok(
+                    "<securityGroupInfo><item><groupId>sg-default</groupId><groupName>default</groupName></item><item><groupId>sg-pal</groupId><groupName>PalWorld</groupName></item></securityGroupInfo>".to_string()
+                ),
+                ("RunInstances", _) => {
+                    let instance_id =
+                        format!("i-{:017x}", self.launched.fetch_add(1, Ordering::SeqCst));
+                    self.instances
+                        .lock()
+                        .unwrap()
+                        .insert(instance_id.clone(), "running");
+                    Ok(format!(
+                        "<reservationId>r-1</reservationId><instancesSet><item><instanceId>{instance_id}</instanceId><instanceState>{}</instanceState></item></instancesSet>",
+                        Self::instance_state("pending")
+                    ))
+                }
+                ("DescribeInstances", Some(state)) => {
+                    let ip = match state {
+                        "running" => format!("<ipAddress>{IP}</ipAddress>"),
+                        _ => String::new(),
+                    };
+                    Ok(format!(
+                        "<reservationSet><item><reservationId>r-1</reservationId><instancesSet><item><instanceId>{instance_id}</instanceId><instanceState>{}</instanceState>{ip}</item></instancesSet></item></reservationSet>",
+                        Self::instance_state(state)
+                    ))
+                }
+                ("TerminateInstances", Some(state)) => {
+                    self.instances
+                        .lock()
+                        .unwrap()
+                        .insert(instance_id.clone(), "shutting-down");
+                    Ok(format!(
+                        "<instancesSet><item><instanceId>{instance_id}</instanceId><currentState>{}</currentState><previousState>{}</previousState></item></instancesSet>",
+                        Self::instance_state("shutting-down"),
+                        Self::instance_state(state)
+                    ))
+                }
+                ("DescribeInstances" | "TerminateInstances", None) => Err((
+                    "InvalidInstanceID.NotFound",
+                    format!("The instance ID '{instance_id}' does not exist"),
+                )),
+                _ => Err(("InvalidAction", format!("{action} is not valid"))),
+            };
+            let (status, body) = match result {
+                Ok(result) => (
+                    StatusCode::OK,
+                    format!(
+                        r#"<{action}Response xmlns="http://ec2.amazonaws.com/doc/2016-11-15/"><requestId>req-1</requestId>{result}</{action}Response>"#
+                    ),
+                ),
+                Err((code, message)) => (
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "<Response><Errors><Error><Code>{code}</Code><Message>{message}</Message></Error></Errors><RequestID>req-1</RequestID></Response>"
+                    ),
+                ),
+            };
+            Response::builder()
+                .status(status)
+                .body(Body::from(body))
+                .unwrap()
+        }
+    }
+
+    fn provider(endpoint: &str) -> AwsProvider {
+        let config: AwsProviderConfig = serde_yaml::from_str(&format!(
+            r#"
+access_key_id: ak
+secret_access_key: sk
+amis: {{ {REGION}: ami-0123 }}
+key_name: psm
+endpoint: "{endpoint}"
+"#
+        ))
+        .unwrap();
+        AwsProvider::new(&config)
+    }
+
+    #[tokio::test]
+    async fn instance_lifecycle_through_endpoint() {
+        let ec2 = Ec2StandIn::default();
+        let provider = provider(&ec2.serve());
+
+        let offers = provider
+            .query_spot_paid_price(&[REGION.into()], &[], &ServiceInstanceType::T2C2G)
+            .await
+            .unwrap();
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].zone, format!("{REGION}a"));
+        assert_eq!(offers[0].instance_type, "t3.small");
+        assert_eq!(offers[0].instance_price, 0.0123);
+        let key_ids = provider.query_key_ids().await.unwrap();
+        let groups = provider.query_security_group_ids(REGION).await.unwrap();
+        assert_eq!(groups, ["sg-pal"]);
+
+        let instance_id = provider
+            .run_instance(&offers[0], key_ids, groups)
+            .await
+            .unwrap();
+        let run = ec2.request("RunInstances");
+        assert_eq!(run["ImageId"], "ami-0123");
+        assert_eq!(run["KeyName"], "psm");
+        assert_eq!(run["SecurityGroupId.1"], "sg-pal");
+        assert_eq!(run["Placement.AvailabilityZone"], format!("{REGION}a"));
+        assert_eq!(run["InstanceMarketOptions.MarketType"], "spot");
+
+        let ip = provider.query_instance_ip(REGION, &instance_id).await;
+        assert_eq!(ip.unwrap(), IP);
+        assert!(!provider.is_reclaiming(REGION, &instance_id).await.unwrap());
+        provider
+            .terminate_instance(REGION, &instance_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            ec2.request("TerminateInstances")["InstanceId.1"],
+            instance_id
+        );
+        assert!(provider.is_reclaiming(REGION, &instance_id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unknown_instance_is_reclaimed() {
+        let provider = provider(&Ec2StandIn::default().serve());
+        // ec2 forgets terminated instances after a while, they are reclaimed all the same
+        assert!(provider.is_reclaiming(REGION, "i-gone").await.unwrap());
+        let terminated = provider.terminate_instance(REGION, "i-gone").await;
+        assert!(terminated
+            .unwrap_err()
+            .to_string()
+            .contains("InvalidInstanceID.NotFound"));
+    }
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::constant::ServiceInstanceType;
+
+use super::{poll_instance_ip, CloudProvider, InstanceOffer};
+
+const LOCAL_REGION: &str = "local";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DockerProviderConfig {
+    /// `docker` or `podman`
+    #[serde(default = "default_runtime")]
+    runtime: String,
+    /// image running sshd, with `ssh.user` and its public key prepared
+    image: String,
+    #[serde(default)]
+    network: Option<String>,
+    /// `host_path:container_path` passed by `-v`
+    #[serde(default)]
+    volumes: Vec<String>,
+    #[serde(default)]
+    extra_args: Vec<String>,
+}
+
+fn default_runtime() -> String {
+    "docker".into()
+}
+
+/// Run the server in a local container instead of a cloud instance.
+pub struct DockerProvider {
+    config: DockerProviderConfig,
+}
+
+impl DockerProvider {
+    pub fn new(config: &DockerProviderConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    async fn exec(&self, args: &[&str]) -> anyhow::Result<String> {
+        debug!("{} {}", self.config.runtime, args.join(" "));
+        let output = Command::new(&self.config.runtime)
+            .args(args)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "{} {} failed: {}",
+                self.config.runtime,
+                args.first().unwrap_or(&""),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+#[async_trait]
+impl CloudProvider for DockerProvider {
+    fn default_regions(&self) -> Vec<String> {
+        vec![LOCAL_REGION.into()]
+    }
+
+    fn is_known_region(&self, region: &str) -> bool {
+        region == LOCAL_REGION
+    }
+
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        _excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        if !candidate_regions.iter().any(|r| r == LOCAL_REGION) {
+            return Err(anyhow::anyhow!(
+                "docker provider only serves region {LOCAL_REGION}"
+            ));
+        }
+        let (cpu, memory) = instance_type.spec();
+        Ok(vec![InstanceOffer {
+            region: LOCAL_REGION.into(),
+            zone: LOCAL_REGION.into(),
+            instance_type: format!("{cpu}c{memory}g"),
+            instance_price: 0.0,
+            bandwidth_price: 0.0,
+        }])
+    }
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn query_security_group_ids(&self, _region: &str) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        _key_ids: Vec<String>,
+        _security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String> {
+        let (cpu, memory) = ServiceInstanceType::try_from(offer.instance_type.clone())?.spec();
+        let cpus = cpu.to_string();
+        let memory = format!("{memory}g");
+        let mut args = vec!["run", "-d", "--cpus", &cpus, "--memory", &memory];
+        if let Some(network) = &self.config.network {
+            args.extend(["--network", network]);
+        }
+        for volume in &self.config.volumes {
+            args.extend(["-v", volume]);
+        }
+        args.extend(self.config.extra_args.iter().map(String::as_str));
+        args.push(&self.config.image);
+        self.exec(&args).await
+    }
+
+    async fn query_instance_ip(&self, _region: &str, instance_id: &str) -> anyhow::Result<String> {
+        poll_instance_ip("container", Duration::from_secs(2), || async {
+            let state = self
+                .exec(&[
+                    "inspect",
+                    "-f",
+                    "{{.State.Running}} {{range .NetworkSettings.Networks}}{{.IPAddress}} {{end}}",
+                    instance_id,
+                ])
+                .await?;
+            let mut state = state.split_whitespace();
+            Ok(match state.next() {
+                Some("true") => state.next().map(str::to_string),
+                _ => None,
+            })
+        })
+        .await
+    }
+
+    async fn terminate_instance(&self, _region: &str, instance_id: &str) -> anyhow::Result<()> {
+        self.exec(&["rm", "-f", instance_id]).await?;
+        Ok(())
+    }
+
+    async fn modify_firewall_rules(
+        &self,
+        _region: &str,
+        _instance_id: &str,
+        _protocol: &str,
+        _port: &str,
+        _cidr_block: &str,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("docker provider has no firewall rules"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// run, find and terminate a real container. needs docker and an image that keeps running,
+    /// the host must have 2 cpus for the 2c2g offer:
+    /// `PSM_TEST_DOCKER_IMAGE=nginx:alpine cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn container_lifecycle() {
+        let image = std::env::var("PSM_TEST_DOCKER_IMAGE").expect("PSM_TEST_DOCKER_IMAGE");
+        let config: DockerProviderConfig =
+            serde_yaml::from_str(&format!("image: {image}")).unwrap();
+        let provider = DockerProvider::new(&config);
+
+        let offers = provider
+            .query_spot_paid_price(&[LOCAL_REGION.into()], &[], &ServiceInstanceType::T2C2G)
+            .await
+            .unwrap();
+        let instance_id = provider
+            .run_instance(&offers[0], vec![], vec![])
+            .await
+            .unwrap();
+        let ip = provider.query_instance_ip(LOCAL_REGION, &instance_id).await;
+        provider
+            .terminate_instance(LOCAL_REGION, &instance_id)
+            .await
+            .unwrap();
+        let ip = ip.unwrap();
+        assert!(ip.parse::<std::net::IpAddr>().is_ok(), "{ip}");
+        assert!(provider.exec(&["inspect", &instance_id]).await.is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::constant::ServiceInstanceType;
+
+use super::{poll_instance_ip, sort_offers, CloudProvider, InstanceOffer};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HetznerProviderConfig {
+    token: String,
+    #[serde(default = "default_image")]
+    image: String,
+    /// locations to search when nothing else is specified
+    #[serde(default = "default_locations")]
+    locations: Vec<String>,
+    /// custom api endpoint, e.g. a local stand-in
+    #[serde(default = "default_endpoint")]
+    endpoint: String,
+}
+
+fn default_image() -> String {
+    "ubuntu-22.04".into()
+}
+
+const KNOWN_LOCATIONS: &[&str] = &["fsn1", "nbg1", "hel1", "ash", "hil", "sin"];
+
+fn default_locations() -> Vec<String> {
+    vec!["fsn1".into(), "nbg1".into(), "hel1".into()]
+}
+
+fn default_endpoint() -> String {
+    "https://api.hetzner.cloud/v1".into()
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerPrice {
+    gross: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerTypePrice {
+    location: String,
+    price_hourly: HetznerPrice,
+    #[serde(default)]
+    price_per_tb_traffic: Option<HetznerPrice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerType {
+    name: String,
+    prices: Vec<ServerTypePrice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerTypes {
+    server_types: Vec<ServerType>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedResource {
+    id: u64,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SshKeys {
+    ssh_keys: Vec<NamedResource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Firewalls {
+    firewalls: Vec<NamedResource>,
+}
+
+#[derive(Debug, Serialize)]
+struct FirewallRef {
+    firewall: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateServer<'a> {
+    name: String,
+    server_type: &'a str,
+    location: &'a str,
+    image: &'a str,
+    ssh_keys: Vec<u64>,
+    firewalls: Vec<FirewallRef>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ipv4 {
+    ip: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublicNet {
+    ipv4: Option<Ipv4>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerServer {
+    id: u64,
+    status: String,
+    public_net: PublicNet,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerResponse {
+    server: HetznerServer,
+}
+
+/// Hetzner Cloud style on-demand vps, billed hourly.
+pub struct HetznerProvider {
+    config: HetznerProviderConfig,
+    client: Client,
+}
+
+impl HetznerProvider {
+    pub fn new(config: &HetznerProviderConfig) -> Self {
+        Self {
+            config: config.clone(),
+            client: Client::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.endpoint.trim_end_matches('/'), path)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        Ok(self
+            .client
+            .get(self.url(path))
+            .bearer_auth(&self.config.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    fn parse_ids(ids: Vec<String>) -> anyhow::Result<Vec<u64>> {
+        ids.iter()
+            .map(|id| Ok(id.parse()?))
+            .collect::<anyhow::Result<_>>()
+    }
+}
+
+#[async_trait]
+impl CloudProvider for HetznerProvider {
+    fn default_regions(&self) -> Vec<String> {
+        self.config.locations.clone()
+    }
+
+    /// configured locations count too, hetzner adds locations over time
+    fn is_known_region(&self, region: &str) -> bool {
+        KNOWN_LOCATIONS.contains(&region) || self.config.locations.iter().any(|l| l == region)
+    }
+
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        let candidate_instance_type = instance_type.to_hetzner_list();
+        let server_types: ServerTypes = self.get("/server_types?per_page=50").await?;
+        let offers = server_types
+            .server_types
+            .iter()
+            .filter(|t| candidate_instance_type.contains(&t.name.as_str()))
+            .flat_map(|t| {
+                t.prices
+                    .iter()
+                    .filter(|p| {
+                        candidate_regions.contains(&p.location)
+                            && !excluded_zones.contains(&p.location)
+                    })
+                    .filter_map(|p| {
+                        Some(InstanceOffer {
+                            region: p.location.clone(),
+                            zone: p.location.clone(),
+                            instance_type: t.name.clone(),
+                            instance_price: p.price_hourly.gross.parse().ok()?,
+                            bandwidth_price: p
+                                .price_per_tb_traffic
+                                .as_ref()
+                                .and_then(|tb| tb.gross.parse::<f64>().ok())
+                                .map_or(0.0, |tb| tb / 1000.0),
+                        })
+                    })
+            })
+            .collect();
+        sort_offers(offers, candidate_instance_type)
+    }
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>> {
+        let keys: SshKeys = self.get("/ssh_keys").await?;
+        Ok(keys.ssh_keys.iter().map(|k| k.id.to_string()).collect())
+    }
+
+    async fn query_security_group_ids(&self, _region: &str) -> anyhow::Result<Vec<String>> {
+        // hetzner firewalls are global
+        let firewalls: Firewalls = self.get("/firewalls").await?;
+        Ok(firewalls
+            .firewalls
+            .iter()
+            .filter(|f| f.name.to_ascii_lowercase().contains("palworld"))
+            .map(|f| f.id.to_string())
+            .collect())
+    }
+
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        key_ids: Vec<String>,
+        security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let body = CreateServer {
+            name: format!("psm-{timestamp}"),
+            server_type: &offer.instance_type,
+            location: &offer.zone,
+            image: &self.config.image,
+            ssh_keys: Self::parse_ids(key_ids)?,
+            firewalls: Self::parse_ids(security_group_ids)?
+                .into_iter()
+                .map(|firewall| FirewallRef { firewall })
+                .collect(),
+        };
+        let resp: ServerResponse = self
+            .client
+            .post(self.url("/servers"))
+            .bearer_auth(&self.config.token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(resp.server.id.to_string())
+    }
+
+    async fn query_instance_ip(&self, _region: &str, instance_id: &str) -> anyhow::Result<String> {
+        poll_instance_ip("server", Duration::from_secs(5), || async {
+            let resp: ServerResponse = self.get(&format!("/servers/{instance_id}")).await?;
+            if resp.server.status != "running" {
+                return Ok(None);
+            }
+            resp.server
+                .public_net
+                .ipv4
+                .map(|ipv4| Some(ipv4.ip))
+                .ok_or(anyhow::anyhow!("running server without ip?"))
+        })
+        .await
+    }
+
+    async fn terminate_instance(&self, _region: &str, instance_id: &str) -> anyhow::Result<()> {
+        self.client
+            .delete(self.url(&format!("/servers/{instance_id}")))
+            .bearer_auth(&self.config.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn modify_firewall_rules(
+        &self,
+        _region: &str,
+        _instance_id: &str,
+        _protocol: &str,
+        _port: &str,
+        _cidr_block: &str,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "hetzner provider does not manage nps firewall"
+        ))
+    }
+}
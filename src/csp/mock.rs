@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Deserialize;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::constant::ServiceInstanceType;
+
+use super::{sort_offers, CloudProvider, InstanceOffer};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MockProviderConfig {
+    /// region -> zones
+    regions: HashMap<String, Vec<String>>,
+    /// hourly price of zones not listed in `zone_prices`
+    #[serde(default = "default_price")]
+    price: f64,
+    #[serde(default)]
+    zone_prices: HashMap<String, f64>,
+    #[serde(default)]
+    bandwidth_price: f64,
+    /// seconds before a created instance turns running
+    #[serde(default)]
+    boot_delay: u64,
+    /// ip assigned to every instance, point it to a local sshd to run scripts
+    #[serde(default = "default_ip")]
+    ip: String,
+    /// chance of `run_instance` failing for lack of stock, 0.0 ~ 1.0
+    #[serde(default)]
+    failure_rate: f64,
+    /// seconds after running before an instance is reported as being reclaimed
+    #[serde(default)]
+    reclaim_after: Option<u64>,
+    /// instances never get an ip
+    #[serde(default)]
+    boot_failure: bool,
+    /// `terminate_instance` always fails, instances keep running
+    #[serde(default)]
+    terminate_failure: bool,
+}
+
+fn default_price() -> f64 {
+    1.0
+}
+
+fn default_ip() -> String {
+    "127.0.0.1".into()
+}
+
+#[derive(Debug)]
+struct MockInstance {
+    region: String,
+    running_at: Instant,
+    reclaim_at: Option<Instant>,
+}
+
+/// In-process cloud provider, nothing leaves the machine.
+pub struct MockProvider {
+    config: MockProviderConfig,
+    next_id: AtomicU64,
+    instances: Mutex<HashMap<String, MockInstance>>,
+}
+
+impl MockProvider {
+    pub fn new(config: &MockProviderConfig) -> Self {
+        Self {
+            config: config.clone(),
+            next_id: AtomicU64::new(1),
+            instances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn instance_type_name(instance_type: &ServiceInstanceType) -> String {
+        format!("MOCK.{instance_type:?}")
+    }
+}
+
+#[async_trait]
+impl CloudProvider for MockProvider {
+    fn default_regions(&self) -> Vec<String> {
+        let mut regions: Vec<_> = self.config.regions.keys().cloned().collect();
+        regions.sort();
+        regions
+    }
+
+    fn is_known_region(&self, region: &str) -> bool {
+        self.config.regions.contains_key(region)
+    }
+
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        let offers = candidate_regions
+            .iter()
+            .filter_map(|region| Some((region, self.config.regions.get(region)?)))
+            .flat_map(|(region, zones)| {
+                zones
+                    .iter()
+                    .filter(|zone| !excluded_zones.contains(zone))
+                    .map(move |zone| InstanceOffer {
+                        region: region.clone(),
+                        zone: zone.clone(),
+                        instance_type: Self::instance_type_name(instance_type),
+                        instance_price: *self
+                            .config
+                            .zone_prices
+                            .get(zone)
+                            .unwrap_or(&self.config.price),
+                        bandwidth_price: self.config.bandwidth_price,
+                    })
+            })
+            .collect();
+        sort_offers(offers, Self::instance_type_name(instance_type))
+    }
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec!["mock-key".into()])
+    }
+
+    async fn query_security_group_ids(&self, _region: &str) -> anyhow::Result<Vec<String>> {
+        Ok(vec!["mock-palworld".into()])
+    }
+
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        _key_ids: Vec<String>,
+        _security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String> {
+        if rand::thread_rng().gen_bool(self.config.failure_rate.clamp(0.0, 1.0)) {
+            return Err(anyhow::anyhow!(
+                "{} sold out in {}",
+                offer.instance_type,
+                offer.zone
+            ));
+        }
+        let instance_id = format!("mock-{}", self.next_id.fetch_add(1, Ordering::SeqCst));
+        info!("mock run instance {instance_id} in {}", offer.zone);
+        let running_at = Instant::now() + Duration::from_secs(self.config.boot_delay);
+        self.instances.lock().unwrap().insert(
+            instance_id.clone(),
+            MockInstance {
+                region: offer.region.clone(),
+                running_at,
+                reclaim_at: self
+                    .config
+                    .reclaim_after
+                    .map(|secs| running_at + Duration::from_secs(secs)),
+            },
+        );
+        Ok(instance_id)
+    }
+
+    async fn query_instance_ip(&self, region: &str, instance_id: &str) -> anyhow::Result<String> {
+        let running_at = self
+            .instances
+            .lock()
+            .unwrap()
+            .get(instance_id)
+            .filter(|i| i.region == region)
+            .map(|i| i.running_at)
+            .ok_or(anyhow::anyhow!("instance {instance_id} not found"))?;
+        if self.config.boot_failure {
+            return Err(anyhow::anyhow!(
+                "instance {instance_id} never turned running"
+            ));
+        }
+        tokio::time::sleep_until(running_at).await;
+        Ok(self.config.ip.clone())
+    }
+
+    async fn terminate_instance(&self, region: &str, instance_id: &str) -> anyhow::Result<()> {
+        if self.config.terminate_failure {
+            return Err(anyhow::anyhow!("mock terminate of {instance_id} failed"));
+        }
+        let mut instances = self.instances.lock().unwrap();
+        match instances.get(instance_id) {
+            Some(i) if i.region == region => {
+                instances.remove(instance_id);
+                info!("mock terminate instance {instance_id}");
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!("instance {instance_id} not found")),
+        }
+    }
+
+    async fn is_reclaiming(&self, region: &str, instance_id: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .instances
+            .lock()
+            .unwrap()
+            .get(instance_id)
+            .filter(|i| i.region == region)
+            .is_none_or(|i| {
+                i.reclaim_at
+                    .is_some_and(|reclaim_at| Instant::now() >= reclaim_at)
+            }))
+    }
+
+    async fn modify_firewall_rules(
+        &self,
+        _region: &str,
+        instance_id: &str,
+        protocol: &str,
+        port: &str,
+        cidr_block: &str,
+    ) -> anyhow::Result<()> {
+        info!("mock firewall {instance_id} {protocol}:{port} allow {cidr_block}");
+        Ok(())
+    }
+}
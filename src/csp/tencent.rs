@@ -0,0 +1,179 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use tencentcloud_sdk::{
+    client::TencentCloudClient,
+    config::ClientConfig,
+    constant::{InstanceType, Region},
+};
+
+use crate::{
+    constant::ServiceInstanceType,
+    cvm_utils::{is_cvm_gone, query_cvm_ip, query_key_ids, query_spot_paid_price},
+};
+
+use super::{CloudProvider, InstanceOffer};
+
+pub struct TencentCloudProvider {
+    client: TencentCloudClient,
+}
+
+impl TencentCloudProvider {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            client: TencentCloudClient::new(config),
+        }
+    }
+}
+
+fn parse_region(region: &str) -> anyhow::Result<Region> {
+    Region::from_str(region).map_err(|e| anyhow::anyhow!("unknown region {region}: {e:?}"))
+}
+
+fn parse_instance_type(instance_type: &str) -> anyhow::Result<InstanceType> {
+    InstanceType::from_str(instance_type)
+        .map_err(|e| anyhow::anyhow!("unknown instance type {instance_type}: {e:?}"))
+}
+
+#[async_trait]
+impl CloudProvider for TencentCloudProvider {
+    fn default_regions(&self) -> Vec<String> {
+        [Region::Guangzhou, Region::Nanjing, Region::Shanghai]
+            .iter()
+            .map(|r| r.to_string())
+            .collect()
+    }
+
+    fn is_known_region(&self, region: &str) -> bool {
+        parse_region(region).is_ok()
+    }
+
+    async fn query_spot_paid_price(
+        &self,
+        candidate_regions: &[String],
+        excluded_zones: &[String],
+        instance_type: &ServiceInstanceType,
+    ) -> anyhow::Result<Vec<InstanceOffer>> {
+        let candidate_regions = candidate_regions
+            .iter()
+            .map(|r| parse_region(r))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let price_result = query_spot_paid_price(
+            &self.client,
+            &candidate_regions,
+            excluded_zones,
+            instance_type,
+        )
+        .await?;
+        Ok(price_result
+            .into_iter()
+            .map(|(price, (region, zone, instance_type))| InstanceOffer {
+                region: region.to_string(),
+                zone,
+                instance_type: instance_type.to_string(),
+                instance_price: price.instance_price.unit_price_discount,
+                bandwidth_price: price.bandwidth_price.unit_price_discount,
+            })
+            .collect())
+    }
+
+    async fn query_key_ids(&self) -> anyhow::Result<Vec<String>> {
+        query_key_ids(&self.client).await
+    }
+
+    async fn query_security_group_ids(&self, region: &str) -> anyhow::Result<Vec<String>> {
+        let region = parse_region(region)?;
+        Ok(self
+            .client
+            .cvm()
+            .security_group()
+            .describe_security_groups(&region)
+            .await?
+            .into_iter()
+            .filter_map(|sg| {
+                sg.security_group_name
+                    .to_ascii_lowercase()
+                    .contains("palworld")
+                    .then_some(sg.security_group_id)
+            })
+            .collect())
+    }
+
+    async fn run_instance(
+        &self,
+        offer: &InstanceOffer,
+        key_ids: Vec<String>,
+        security_group_ids: Vec<String>,
+    ) -> anyhow::Result<String> {
+        let region = parse_region(&offer.region)?;
+        let instance_type = parse_instance_type(&offer.instance_type)?;
+        Ok(self
+            .client
+            .cvm()
+            .instances()
+            .run_instance(
+                &region,
+                &offer.zone,
+                &instance_type,
+                key_ids,
+                security_group_ids,
+            )
+            .await?)
+    }
+
+    async fn query_instance_ip(&self, region: &str, instance_id: &str) -> anyhow::Result<String> {
+        let region = parse_region(region)?;
+        query_cvm_ip(&self.client, &region, instance_id).await
+    }
+
+    async fn terminate_instance(&self, region: &str, instance_id: &str) -> anyhow::Result<()> {
+        let region = parse_region(region)?;
+        self.client
+            .cvm()
+            .instances()
+            .terminate_instance(&region, instance_id)
+            .await?;
+        Ok(())
+    }
+
+    fn termination_notice_url(&self) -> Option<&'static str> {
+        Some("http://metadata.tencentyun.com/latest/meta-data/spot/termination-time")
+    }
+
+    async fn is_reclaiming(&self, region: &str, instance_id: &str) -> anyhow::Result<bool> {
+        let region = parse_region(region)?;
+        is_cvm_gone(&self.client, &region, instance_id).await
+    }
+
+    async fn modify_firewall_rules(
+        &self,
+        region: &str,
+        instance_id: &str,
+        protocol: &str,
+        port: &str,
+        cidr_block: &str,
+    ) -> anyhow::Result<()> {
+        let region = parse_region(region)?;
+        let mut firewallrules = self
+            .client
+            .lighthouse()
+            .firewall()
+            .describe_firewall_rules(&region, instance_id)
+            .await
+            .unwrap_or_default()
+            .response
+            .firewall_rule_set;
+        firewallrules
+            .iter_mut()
+            .filter(|r| r.port == port && r.protocol == protocol)
+            .for_each(|r| {
+                r.cidr_block = cidr_block.to_string();
+            });
+        self.client
+            .lighthouse()
+            .firewall()
+            .modify_firewall_rules(&region, instance_id, firewallrules)
+            .await?;
+        Ok(())
+    }
+}
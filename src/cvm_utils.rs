@@ -8,16 +8,16 @@ use tencentcloud_sdk::{
     },
     constant::{InstanceType, Region},
 };
-use tokio::time::{sleep, Instant};
 
-use crate::constant::ServiceInstanceType;
+use crate::{constant::ServiceInstanceType, csp::poll_instance_ip};
 
-/// return cheapest (price, (region, zone, instance_type))
+/// return all (price, (region, zone, instance_type)), cheapest first
 pub async fn query_spot_paid_price(
     client: &TencentCloudClient,
     candidate_regions: &[Region],
+    excluded_zones: &[String],
     instance_type: &ServiceInstanceType,
-) -> anyhow::Result<(Price, (Region, String, InstanceType))> {
+) -> anyhow::Result<Vec<(Price, (Region, String, InstanceType))>> {
     let candidate_instance_type = instance_type.to_list();
 
     let mut handles = vec![];
@@ -27,6 +27,7 @@ pub async fn query_spot_paid_price(
         if let Some(zones) = zones {
             for (zone, instance_type) in zones
                 .iter()
+                .filter(|z| !excluded_zones.contains(z))
                 .cartesian_product(candidate_instance_type.iter())
             {
                 let client = client.clone();
@@ -60,9 +61,12 @@ pub async fn query_spot_paid_price(
             .unit_price_discount
             .total_cmp(&b.0.instance_price.unit_price_discount)
     });
-    price_result.into_iter().next().ok_or(anyhow::anyhow!(
-        "failed to get any available instance of {candidate_instance_type:?}"
-    ))
+    if price_result.is_empty() {
+        return Err(anyhow::anyhow!(
+            "failed to get any available instance of {candidate_instance_type:?}"
+        ));
+    }
+    Ok(price_result)
 }
 
 pub async fn query_key_ids(client: &TencentCloudClient) -> anyhow::Result<Vec<String>> {
@@ -74,38 +78,46 @@ pub async fn query_key_ids(client: &TencentCloudClient) -> anyhow::Result<Vec<St
         .map(|vk| vk.into_iter().map(|k| k.key_id).collect())
 }
 
+/// whether the cvm is shutting down or terminating, like a reclaimed spot instance.
+/// instances are listed a page at a time, one missing from it is not taken as gone
+pub async fn is_cvm_gone(
+    client: &TencentCloudClient,
+    region: &Region,
+    instance_id: &str,
+) -> anyhow::Result<bool> {
+    let resp = client.cvm().instances().describe_instance(region).await?;
+    Ok(resp
+        .response
+        .instance_set
+        .iter()
+        .find(|i| i.instance_id == instance_id)
+        .is_some_and(|i| {
+            matches!(
+                i.instance_state,
+                InstanceState::SHUTDOWN | InstanceState::TERMINATING
+            )
+        }))
+}
+
 pub async fn query_cvm_ip(
     client: &TencentCloudClient,
     region: &Region,
     instance_id: &str,
 ) -> anyhow::Result<String> {
-    let timeout_duration = Duration::from_secs(62);
-    let start_time = Instant::now();
-
-    loop {
-        // 进行轮询查询的操作
-        // ...
+    poll_instance_ip("cvm", Duration::from_secs(5), || async {
         let resp = client.cvm().instances().describe_instance(region).await?;
-
-        if let Some(instance) =
+        let Some(instance) =
             resp.response.instance_set.into_iter().find(|i| {
                 i.instance_id == instance_id && i.instance_state == InstanceState::RUNNING
             })
-        {
-            break instance
-                .public_ip_addresses
-                .ok_or(anyhow::anyhow!(format!("running cvm without ip?")))?
-                .into_iter()
-                .next()
-                .ok_or(anyhow::anyhow!(format!("running cvm without ip?")));
-        }
-
-        // 检查是否超时
-        if Instant::now() - start_time >= timeout_duration {
-            break Err(anyhow::anyhow!("query cvm create status and ip timeout"));
-        }
-
-        // 等待一段时间再进行下一次轮询
-        sleep(Duration::from_secs(5)).await;
-    }
+        else {
+            return Ok(None);
+        };
+        instance
+            .public_ip_addresses
+            .and_then(|ips| ips.into_iter().next())
+            .map(Some)
+            .ok_or(anyhow::anyhow!("running cvm without ip?"))
+    })
+    .await
 }
@@ -1,45 +1,247 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Datelike, Duration, NaiveDateTime};
 use opendal::{
-    services::{Fs, Sftp},
-    Operator,
+    services::{Fs, Webdav, S3},
+    Entry, ErrorKind, Operator, Writer,
 };
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::SaveStorageConfig,
+    save_crypto::{
+        Decryptor, EncryptionConfig, Encryptor, SaveCipher, MAGIC, PREFIX_SIZE, TAG_SIZE,
+    },
+    shell_manager::RemoteShell,
+};
+
+/// saves are transferred in chunks of this size, bounding memory use
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
 
-use crate::config::SshConfig;
+/// transfer progress callback, (transferred bytes, total bytes)
+pub type Progress<'a> = &'a (dyn Fn(u64, u64) + Send + Sync);
+
+/// presigned urls given to instances stay valid for this long
+const PRESIGN_EXPIRE: std::time::Duration = std::time::Duration::from_secs(3600);
+/// curl on the instance gives up a presigned transfer after this, so a stalled one releases the save lock
+const PRESIGN_TRANSFER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1800);
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LocalSaveStorageConfig {
     local_dir: String,
     remote_dir: String,
+    #[serde(default)]
+    retention: RetentionConfig,
+    /// encrypt saves at rest, only the primary's is used
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+/// s3 compatible bucket holding saves, e.g. tencent cos or minio. scripts still come from `local_dir`
+#[derive(Debug, Deserialize, Clone)]
+pub struct S3SaveStorageConfig {
+    #[serde(flatten)]
+    pub local: LocalSaveStorageConfig,
+    bucket: String,
+    endpoint: String,
+    #[serde(default = "default_s3_region")]
+    region: String,
+    /// prefix of saves in the bucket
+    #[serde(default)]
+    root: String,
+    access_key_id: String,
+    secret_access_key: String,
+    /// `<bucket>.<endpoint>` addressing, required by tencent cos
+    #[serde(default)]
+    virtual_host_style: bool,
+    /// instances pull and push saves with presigned urls instead of relaying through the manager
+    #[serde(default)]
+    presign: bool,
+}
+
+fn default_s3_region() -> String {
+    "auto".into()
+}
+
+/// webdav share holding saves, e.g. a nas. saves are always relayed through the manager
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebdavSaveStorageConfig {
+    #[serde(flatten)]
+    pub local: LocalSaveStorageConfig,
+    endpoint: String,
+    /// directory of saves in the share
+    #[serde(default)]
+    root: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    /// bearer token, instead of username and password
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// which saves of each server to keep in `local_dir/saves/<server>`, nothing is pruned if all unset
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RetentionConfig {
+    /// keep the newest N saves, 0 only makes sense with daily or weekly
+    pub keep_last: Option<usize>,
+    /// keep the newest save of each day within D days
+    pub keep_daily: Option<i64>,
+    /// keep the newest save of each week within W weeks
+    pub keep_weekly: Option<i64>,
+}
+
+impl RetentionConfig {
+    fn is_unset(&self) -> bool {
+        self.keep_last.is_none() && self.keep_daily.is_none() && self.keep_weekly.is_none()
+    }
+
+    /// `keep_last: 0` alone would delete every save but the protected ones
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.keep_last != Some(0) || self.keep_daily.is_some() || self.keep_weekly.is_some(),
+            "retention keep_last 0 keeps no save, set keep_daily or keep_weekly with it"
+        );
+        Ok(())
+    }
+
+    /// saves to delete, names not like `Saved.<%Y%m%d%H%M%S>.tar.gz` are always kept.
+    /// days and weeks count back from the newest save, not from now
+    fn expired(&self, names: &[String]) -> Vec<String> {
+        let mut saves: Vec<_> = names
+            .iter()
+            .filter_map(|name| Some((save_time(name)?, name)))
+            .collect();
+        saves.sort_by_key(|s| std::cmp::Reverse(s.0));
+        let Some(&(newest, _)) = saves.first() else {
+            return vec![];
+        };
+
+        let mut keep = HashSet::new();
+        keep.extend(saves.iter().take(self.keep_last.unwrap_or(0)).map(|s| s.1));
+        if let Some(days) = self.keep_daily {
+            let mut daily = HashMap::new();
+            saves
+                .iter()
+                .filter(|(time, _)| newest - *time < Duration::days(days))
+                .for_each(|(time, name)| {
+                    daily.entry(time.date()).or_insert(*name);
+                });
+            keep.extend(daily.into_values());
+        }
+        if let Some(weeks) = self.keep_weekly {
+            let mut weekly = HashMap::new();
+            saves
+                .iter()
+                .filter(|(time, _)| newest - *time < Duration::weeks(weeks))
+                .for_each(|(time, name)| {
+                    weekly.entry(time.iso_week()).or_insert(*name);
+                });
+            keep.extend(weekly.into_values());
+        }
+        saves
+            .into_iter()
+            .filter(|(_, name)| !keep.contains(name))
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+}
+
+pub struct SaveInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+impl Display for SaveInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = save_time(&self.name)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or("unknown time".into());
+        write!(
+            f,
+            "{} {:.1}MB {time}",
+            self.name,
+            self.size as f64 / 1024.0 / 1024.0
+        )
+    }
+}
+
+/// outcome of writing a save to one replica
+pub struct ReplicaReport {
+    pub replica: String,
+    pub error: Option<String>,
+}
+
+impl Display for ReplicaReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            None => write!(f, "{} ok", self.replica),
+            Some(e) => write!(f, "{} failed: {e}", self.replica),
+        }
+    }
+}
+
+/// only `Saved.<timestamp>.tar.gz` names are accepted from bot input, also keeps them shell safe
+pub fn is_save_name(name: &str) -> bool {
+    save_time(name).is_some()
+}
+
+fn save_time(name: &str) -> Option<NaiveDateTime> {
+    let time = name.strip_prefix("Saved.")?.strip_suffix(".tar.gz")?;
+    NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%S").ok()
 }
 
 #[derive(Debug)]
 pub struct LocalStorage {
+    /// local dirs of the primary, scripts and retention come from it
     config: LocalSaveStorageConfig,
+    /// save backends, the first one is the primary
+    replicas: Vec<SaveStorageConfig>,
+    cipher: Option<SaveCipher>,
 }
 
 impl LocalStorage {
-    pub fn new(config: LocalSaveStorageConfig) -> Self {
-        Self { config }
+    pub fn new(replicas: Vec<SaveStorageConfig>) -> anyhow::Result<Self> {
+        let config = replicas[0].local().clone();
+        config.retention.check()?;
+        let cipher = config
+            .encryption
+            .as_ref()
+            .map(SaveCipher::new)
+            .transpose()?;
+        Ok(Self {
+            config,
+            replicas,
+            cipher,
+        })
+    }
+    /// instances can't read sealed saves, so they are always relayed when encrypting
+    fn presign(&self, storage: &SaveStorageConfig) -> bool {
+        self.cipher.is_none() && is_presign(storage)
     }
     fn build_local_op(&self) -> anyhow::Result<Operator> {
-        let mut fs = Fs::default();
-        fs.root(&self.config.local_dir);
-        Ok(Operator::new(fs)?.finish())
-    }
-    fn build_remote_sftp(&self, ssh: &SshConfig, ip: &str) -> anyhow::Result<Operator> {
-        let endpoint = format!("ssh://{}@{}:22", ssh.user, ip);
-        let mut sftp = Sftp::default();
-        sftp.root(&self.config.remote_dir)
-            .endpoint(&endpoint)
-            .key(&ssh.prikey)
-            .user(&ssh.user)
-            .known_hosts_strategy("Accept");
-        Ok(Operator::new(sftp)?.finish())
-    }
-
-    pub async fn upload_scripts(&self, ssh: &SshConfig, ip: &str) -> anyhow::Result<()> {
+        build_fs(&self.config.local_dir)
+    }
+    fn replica_name(&self, index: usize) -> String {
+        let kind = self.replicas[index].kind();
+        match index {
+            0 => format!("{kind}(primary)"),
+            _ => format!("{kind}#{index}"),
+        }
+    }
+
+    pub async fn upload_scripts(&self, shell: &dyn RemoteShell, ip: &str) -> anyhow::Result<()> {
         let local_op = self.build_local_op()?;
-        let remote_op = self.build_remote_sftp(ssh, ip)?;
+        let remote_op = shell.files(ip, &self.config.remote_dir)?;
 
         let files = &[
             "install_server.sh",
@@ -56,35 +258,1573 @@ impl LocalStorage {
         Ok(())
     }
 
+    /// put the save on the instance from the first replica holding it with a matching checksum,
+    /// return (that replica, checksum the restore should verify)
     pub async fn upload_saves(
         &self,
+        server: &str,
         save_name: &str,
-        ssh: &SshConfig,
+        shell: &dyn RemoteShell,
         ip: &str,
-    ) -> anyhow::Result<()> {
-        let local_op = self.build_local_op()?;
-        let remote_op = self.build_remote_sftp(ssh, ip)?;
+        progress: Progress<'_>,
+    ) -> anyhow::Result<(String, String)> {
+        let path = save_path(server, save_name);
+        let mut errors = vec![];
+        for (index, storage) in self.replicas.iter().enumerate() {
+            let replica = self.replica_name(index);
+            match self.upload_from(storage, &path, shell, ip, progress).await {
+                Ok(checksum) => return Ok((replica, checksum)),
+                Err(e) => {
+                    warn!("upload {path} from {replica} failed: {e}");
+                    errors.push(format!("{replica}: {e}"));
+                }
+            }
+        }
+        anyhow::bail!("no replica can provide {save_name}: {}", errors.join("; "))
+    }
 
-        let content = local_op.read(&format!("/saves/{}", save_name)).await?;
-        remote_op
-            .write(&format!("/saves/{}", save_name), content)
-            .await?;
-        Ok(())
+    async fn upload_from(
+        &self,
+        storage: &SaveStorageConfig,
+        path: &str,
+        shell: &dyn RemoteShell,
+        ip: &str,
+        progress: Progress<'_>,
+    ) -> anyhow::Result<String> {
+        let save_op = build_save_op(storage)?;
+        let checksum = read_checksum(&save_op, path).await?;
+        if self.presign(storage) {
+            let req = save_op.presign_read(path, PRESIGN_EXPIRE).await?;
+            let remote_path = format!("{}{path}", self.config.remote_dir);
+            let command = format!(
+                "mkdir -p $(dirname {remote_path}) && curl -sf -m {} -o {remote_path} '{}' && sha256sum {remote_path}",
+                PRESIGN_TRANSFER_TIMEOUT.as_secs(),
+                req.uri()
+            );
+            let pulled = pulled_checksum(shell.exec(ip, &command).await?)?;
+            if let Some(checksum) = &checksum {
+                check_checksum(path, checksum, &pulled)?;
+            }
+            return Ok(pulled);
+        }
+
+        let remote_op = shell.files(ip, &self.config.remote_dir)?;
+        let total = save_op.stat(path).await?.content_length();
+        let source = ChunkSource::open(&save_op, path, self.cipher.as_ref()).await?;
+        let mut sinks = vec![Sink::new(&remote_op, path).await?];
+        let actual = stream_copy(source, &mut sinks, None, total, progress).await?;
+        if let Some(e) = sinks.remove(0).error {
+            anyhow::bail!("upload {path} failed: {e}");
+        }
+        if let Some(checksum) = &checksum {
+            check_checksum(path, checksum, &actual)?;
+        }
+        Ok(actual)
     }
 
+    /// stream the save from the instance to every replica with a checksum sidecar.
+    /// fails if the content doesn't match `checksum` computed on the instance, or the primary can't keep it
     pub async fn download_saves(
         &self,
+        server: &str,
         save_name: &str,
-        ssh: &SshConfig,
+        checksum: &str,
+        shell: &dyn RemoteShell,
         ip: &str,
-    ) -> anyhow::Result<()> {
+        progress: Progress<'_>,
+    ) -> anyhow::Result<Vec<ReplicaReport>> {
+        let path = save_path(server, save_name);
+        // with presign the instance puts it into the primary, which then feeds the other replicas
+        let (source, skip) = if self.presign(&self.replicas[0]) {
+            let primary_op = build_save_op(&self.replicas[0])?;
+            let req = primary_op.presign_write(&path, PRESIGN_EXPIRE).await?;
+            let remote_path = format!("{}{path}", self.config.remote_dir);
+            let command = format!(
+                "curl -sf -m {} -X PUT -T {remote_path} '{}' && echo ok",
+                PRESIGN_TRANSFER_TIMEOUT.as_secs(),
+                req.uri()
+            );
+            check_remote_transfer(shell.exec(ip, &command).await?)?;
+            (primary_op, 1)
+        } else {
+            (shell.files(ip, &self.config.remote_dir)?, 0)
+        };
+
+        self.replicate(skip, &source, &path, &path, Some(checksum), progress)
+            .await
+    }
+
+    /// keep a local archive, e.g. an imported one, in every replica. return its name and replica reports
+    pub async fn import_save(
+        &self,
+        server: &str,
+        archive: &Path,
+    ) -> anyhow::Result<(String, Vec<ReplicaReport>)> {
+        let save_name = archive
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| is_save_name(n))
+            .ok_or(anyhow::anyhow!(
+                "{} is not a save archive",
+                archive.display()
+            ))?;
+        let dir = archive
+            .parent()
+            .and_then(|d| d.to_str())
+            .ok_or(anyhow::anyhow!(
+                "invalid archive path {}",
+                archive.display()
+            ))?;
+        let path = save_path(server, save_name);
+        let reports = self
+            .replicate(0, &build_fs(dir)?, save_name, &path, None, &|_, _| {})
+            .await?;
+        Ok((save_name.to_string(), reports))
+    }
+
+    /// stream `source_path` of `source` to `path` of replicas from `skip` on, then write
+    /// checksum sidecars to all. `checksum` is verified if known, the primary must succeed
+    async fn replicate(
+        &self,
+        skip: usize,
+        source: &Operator,
+        source_path: &str,
+        path: &str,
+        checksum: Option<&str>,
+        progress: Progress<'_>,
+    ) -> anyhow::Result<Vec<ReplicaReport>> {
+        // a secondary replica that can't be reached is only reported, like one failing on the way
+        let mut ops = vec![];
+        let mut sinks = vec![];
+        for (index, storage) in self.replicas.iter().enumerate() {
+            let opened = async {
+                let op = build_save_op(storage)?;
+                let sink = if index < skip {
+                    None
+                } else {
+                    Some(Sink::open(storage, &op, path).await?)
+                };
+                Ok::<_, anyhow::Error>((op, sink))
+            }
+            .await;
+            match opened {
+                Ok((op, sink)) => {
+                    ops.push(Some(op));
+                    sinks.extend(sink);
+                }
+                Err(e) if index == 0 => anyhow::bail!("primary failed to keep {path}: {e}"),
+                Err(e) => {
+                    warn!("open {path} in {} failed: {e}", self.replica_name(index));
+                    ops.push(None);
+                    sinks.push(Sink::failed(e.to_string()));
+                }
+            }
+        }
+        let total = source.stat(source_path).await?.content_length();
+        let seal = self.cipher.as_ref().map(SaveCipher::encryptor);
+        let source = ChunkSource::raw(source, source_path).await?;
+        let actual = stream_copy(source, &mut sinks, seal, total, progress).await?;
+        let mut errors: Vec<_> = (0..skip)
+            .map(|_| None)
+            .chain(sinks.into_iter().map(|s| s.error))
+            .collect();
+        let checksum = checksum.unwrap_or(&actual);
+        if let Err(e) = check_checksum(path, checksum, &actual) {
+            for (op, error) in ops.iter().zip(&errors) {
+                if let (Some(op), None) = (op, error) {
+                    op.delete(path).await?;
+                }
+            }
+            return Err(e);
+        }
+        if let Some(e) = &errors[0] {
+            anyhow::bail!("primary failed to keep {path}: {e}");
+        }
+
+        for (op, error) in ops.iter().zip(errors.iter_mut()) {
+            let Some(op) = op else {
+                continue;
+            };
+            if error.is_none() {
+                if let Err(e) = op.write(&checksum_path(path), checksum.to_string()).await {
+                    *error = Some(e.to_string());
+                }
+            }
+        }
+        if let Some(e) = &errors[0] {
+            anyhow::bail!("primary failed to keep checksum of {path}: {e}");
+        }
+        Ok(errors
+            .into_iter()
+            .enumerate()
+            .map(|(index, error)| ReplicaReport {
+                replica: self.replica_name(index),
+                error,
+            })
+            .collect())
+    }
+
+    /// saves of `server` in the primary, newest first
+    pub async fn list_saves(&self, server: &str) -> anyhow::Result<Vec<SaveInfo>> {
+        list_saves(&build_save_op(&self.replicas[0])?, server).await
+    }
+
+    /// whether any replica has the save
+    pub async fn has_save(&self, server: &str, save_name: &str) -> anyhow::Result<bool> {
+        if !is_save_name(save_name) {
+            return Ok(false);
+        }
+        for storage in &self.replicas {
+            let save_op = build_save_op(storage)?;
+            if save_op.is_exist(&save_path(server, save_name)).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// apply retention to saves of `server` in every replica, never deleting `protected` ones.
+    /// return deleted names
+    pub async fn prune_saves(
+        &self,
+        server: &str,
+        protected: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let retention = &self.config.retention;
+        if retention.is_unset() {
+            return Ok(vec![]);
+        }
+        let mut pruned = vec![];
+        for storage in &self.replicas {
+            let save_op = build_save_op(storage)?;
+            let names: Vec<_> = list_saves(&save_op, server)
+                .await?
+                .into_iter()
+                .map(|s| s.name)
+                .collect();
+            for name in retention.expired(&names) {
+                if protected.contains(&name) {
+                    continue;
+                }
+                let path = save_path(server, &name);
+                save_op.delete(&path).await?;
+                save_op.delete(&checksum_path(&path)).await?;
+                info!("pruned save {server}/{name} in {}", storage.kind());
+                pruned.push(name);
+            }
+        }
+        pruned.sort();
+        pruned.dedup();
+        Ok(pruned)
+    }
+
+    /// seal saves of `servers` in every replica that are plain or sealed with an old key
+    /// with the current key, return how many were sealed
+    pub async fn reencrypt_saves(&self, servers: &[String]) -> anyhow::Result<usize> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or(anyhow::anyhow!("encryption is not configured"))?;
+        let mut count = 0;
+        for storage in &self.replicas {
+            let op = build_save_op(storage)?;
+            for server in servers {
+                for save in list_saves(&op, server).await? {
+                    let path = save_path(server, &save.name);
+                    let source = ChunkSource::open(&op, &path, Some(cipher)).await?;
+                    if source.key_id() == Some(cipher.current_key_id()) {
+                        continue;
+                    }
+                    // seal into a temporary file first, the archive is only replaced once verified
+                    let tmp = format!("{path}.tmp");
+                    let mut sinks = vec![Sink::open(storage, &op, &tmp).await?];
+                    let seal = Some(cipher.encryptor());
+                    let checksum =
+                        stream_copy(source, &mut sinks, seal, save.size, &|_, _| {}).await?;
+                    if let Some(e) = sinks.remove(0).error {
+                        anyhow::bail!("seal {path} failed: {e}");
+                    }
+                    match read_checksum(&op, &path).await? {
+                        Some(expected) => check_checksum(&path, &expected, &checksum)?,
+                        None => op.write(&checksum_path(&path), checksum).await?,
+                    }
+                    let source = ChunkSource::raw(&op, &tmp).await?;
+                    let mut sinks = vec![Sink::open(storage, &op, &path).await?];
+                    stream_copy(source, &mut sinks, None, save.size, &|_, _| {}).await?;
+                    if let Some(e) = sinks.remove(0).error {
+                        anyhow::bail!("replace {path} failed, sealed copy kept in {tmp}: {e}");
+                    }
+                    op.delete(&tmp).await?;
+                    info!("re-encrypted {path} in {}", storage.kind());
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// move saves left in the shared local `/saves/` into `/saves/<server>/`.
+    /// `owners` is (server, current save), archives no server references stay
+    /// unless there is only one server to own them
+    pub async fn migrate_saves(&self, owners: &[(String, Option<String>)]) -> anyhow::Result<()> {
         let local_op = self.build_local_op()?;
-        let remote_op = self.build_remote_sftp(ssh, ip)?;
-        let content = remote_op.read(&format!("/saves/{}", save_name)).await?;
-        local_op
-            .write(&format!("/saves/{}", save_name), content)
+        for entry in list_files(&local_op, "/saves/").await? {
+            let name = entry.name();
+            if !is_save_name(name) {
+                continue;
+            }
+            let owner = owners
+                .iter()
+                .find(|(_, save)| save.as_deref() == Some(name))
+                .or(match owners {
+                    [only] => Some(only),
+                    _ => None,
+                });
+            match owner {
+                Some((server, _)) => {
+                    local_op.create_dir(&format!("/saves/{server}/")).await?;
+                    local_op
+                        .rename(entry.path(), &save_path(server, name))
+                        .await?;
+                    info!("migrated save {name} to server {server}");
+                }
+                None => warn!("save {name} is not referenced by any server, left in /saves/"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn save_path(server: &str, save_name: &str) -> String {
+    format!("/saves/{server}/{save_name}")
+}
+
+/// files directly under `dir`, empty if it doesn't exist yet
+async fn list_files(op: &Operator, dir: &str) -> anyhow::Result<Vec<Entry>> {
+    match op.list(dir).await {
+        Ok(entries) => Ok(entries
+            .into_iter()
+            .filter(|e| e.metadata().is_file())
+            .collect()),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound) => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// one destination of a streamed transfer, stops receiving chunks after its first error.
+/// chunks are buffered into writes the backend takes, s3 multipart parts but the last must
+/// be at least 5MiB
+struct Sink {
+    target: Option<SinkTarget>,
+    buffer: Vec<u8>,
+    /// write once this many bytes are buffered
+    flush_at: usize,
+    /// largest write handed to the backend
+    largest_write: usize,
+    path: String,
+    error: Option<String>,
+}
+
+enum SinkTarget {
+    Writer(Writer),
+    Spool(WebdavSpool),
+}
+
+impl Sink {
+    /// a backend taking many writes
+    async fn new(op: &Operator, path: &str) -> anyhow::Result<Self> {
+        let capability = op.info().full_capability();
+        anyhow::ensure!(
+            capability.write_can_multi,
+            "{} only takes a whole file in one write",
+            op.info().scheme()
+        );
+        let flush_at = capability
+            .write_multi_min_size
+            .map_or(CHUNK_SIZE, |min| min.max(CHUNK_SIZE));
+        Ok(Self::with_target(
+            SinkTarget::Writer(op.writer(path).await?),
+            path,
+            flush_at,
+        ))
+    }
+
+    /// `path` of a replica, a webdav one goes through a spool
+    async fn open(storage: &SaveStorageConfig, op: &Operator, path: &str) -> anyhow::Result<Self> {
+        match storage {
+            SaveStorageConfig::Webdav(config) => Ok(Self::with_target(
+                SinkTarget::Spool(WebdavSpool::new(config, op, path).await?),
+                path,
+                0,
+            )),
+            _ => Self::new(op, path).await,
+        }
+    }
+
+    fn with_target(target: SinkTarget, path: &str, flush_at: usize) -> Self {
+        Self {
+            target: Some(target),
+            buffer: vec![],
+            flush_at,
+            largest_write: 0,
+            path: path.to_string(),
+            error: None,
+        }
+    }
+
+    /// a destination that couldn't be opened, it never receives chunks
+    fn failed(error: String) -> Self {
+        Self {
+            target: None,
+            buffer: vec![],
+            flush_at: 0,
+            largest_write: 0,
+            path: String::new(),
+            error: Some(error),
+        }
+    }
+
+    async fn write(&mut self, chunk: &[u8]) {
+        if self.target.is_none() {
+            return;
+        }
+        self.buffer.extend_from_slice(chunk);
+        if self.buffer.len() >= self.flush_at {
+            self.flush().await;
+        }
+    }
+
+    async fn flush(&mut self) {
+        let Some(target) = &mut self.target else {
+            return;
+        };
+        if self.buffer.is_empty() {
+            return;
+        }
+        let buffer = std::mem::take(&mut self.buffer);
+        self.largest_write = self.largest_write.max(buffer.len());
+        let written = match target {
+            SinkTarget::Writer(writer) => writer.write(buffer).await.map_err(anyhow::Error::from),
+            SinkTarget::Spool(spool) => spool.file.write_all(&buffer).await.map_err(Into::into),
+        };
+        if let Err(e) = written {
+            self.fail(e.to_string()).await;
+        }
+    }
+
+    async fn close(&mut self) {
+        self.flush().await;
+        let closed = match self.target.take() {
+            Some(SinkTarget::Writer(mut writer)) => writer.close().await.map_err(Into::into),
+            Some(SinkTarget::Spool(spool)) => spool.put().await,
+            None => return,
+        };
+        match closed {
+            Ok(()) => debug!(
+                "{} written, largest write {}",
+                self.path, self.largest_write
+            ),
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    async fn fail(&mut self, error: String) {
+        if let Some(SinkTarget::Writer(mut writer)) = self.target.take() {
+            writer.abort().await.unwrap_or_default();
+        }
+        self.buffer = vec![];
+        self.error = Some(error);
+    }
+}
+
+/// webdav only takes a whole file in one PUT, which opendal would hold in memory.
+/// the save is spooled to a local file instead and streamed from there on close
+struct WebdavSpool {
+    file: tokio::fs::File,
+    spool: SpoolPath,
+    op: Operator,
+    path: String,
+    request: reqwest::RequestBuilder,
+}
+
+/// a spool file, removed on drop
+struct SpoolPath(PathBuf);
+
+impl Drop for SpoolPath {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).unwrap_or_default();
+    }
+}
+
+impl WebdavSpool {
+    async fn new(
+        config: &WebdavSaveStorageConfig,
+        op: &Operator,
+        path: &str,
+    ) -> anyhow::Result<Self> {
+        let mut url = reqwest::Url::parse(&config.endpoint)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid webdav endpoint {}", config.endpoint))?
+            .pop_if_empty()
+            .extend(
+                config
+                    .root
+                    .split('/')
+                    .chain(path.split('/'))
+                    .filter(|s| !s.is_empty()),
+            );
+        let mut request = reqwest::Client::new().put(url);
+        if let Some(token) = &config.token {
+            request = request.bearer_auth(token);
+        } else if let Some(username) = &config.username {
+            request = request.basic_auth(username, config.password.as_ref());
+        }
+        let spool = SpoolPath(
+            std::env::temp_dir().join(format!("psm-spool-{:016x}", rand::random::<u64>())),
+        );
+        let file = tokio::fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&spool.0)
             .await?;
+        Ok(Self {
+            file,
+            spool,
+            op: op.clone(),
+            path: path.to_string(),
+            request,
+        })
+    }
 
+    async fn put(self) -> anyhow::Result<()> {
+        // the spool lives until the request is sent
+        let Self {
+            mut file,
+            spool: _spool,
+            op,
+            path,
+            request,
+        } = self;
+        file.flush().await?;
+        let len = file.seek(SeekFrom::End(0)).await?;
+        file.rewind().await?;
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            op.create_dir(&format!("{parent}/")).await?;
+        }
+        request
+            .header(reqwest::header::CONTENT_LENGTH, len)
+            .body(file)
+            .send()
+            .await?
+            .error_for_status()?;
         Ok(())
     }
 }
+
+/// stored bytes of an archive
+type SourceReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// plaintext chunks of a stored archive, of `CHUNK_SIZE` except the last.
+/// sealed archives are opened with their key
+struct ChunkSource {
+    reader: SourceReader,
+    /// bytes read looking for a header, which belong to a plain archive
+    pending: Vec<u8>,
+    decryptor: Option<(String, Decryptor)>,
+    done: bool,
+    /// bytes read as stored, progress is measured in them
+    read: u64,
+}
+
+impl ChunkSource {
+    /// bytes as stored, never decrypted
+    async fn raw(op: &Operator, path: &str) -> anyhow::Result<Self> {
+        Ok(Self::from_reader(Box::new(op.reader(path).await?)))
+    }
+
+    fn from_reader(reader: SourceReader) -> Self {
+        Self {
+            reader,
+            pending: vec![],
+            decryptor: None,
+            done: false,
+            read: 0,
+        }
+    }
+
+    async fn open(op: &Operator, path: &str, cipher: Option<&SaveCipher>) -> anyhow::Result<Self> {
+        Self::open_reader(Box::new(op.reader(path).await?), path, cipher).await
+    }
+
+    async fn open_reader(
+        reader: SourceReader,
+        path: &str,
+        cipher: Option<&SaveCipher>,
+    ) -> anyhow::Result<Self> {
+        let mut source = Self::from_reader(reader);
+        let mut magic = vec![0; MAGIC.len() + 1];
+        let n = read_chunk(&mut source.reader, &mut magic).await?;
+        source.read += n as u64;
+        magic.truncate(n);
+        if n < MAGIC.len() + 1 || !magic.starts_with(MAGIC) {
+            source.pending = magic;
+            return Ok(source);
+        }
+        let cipher = cipher.ok_or(anyhow::anyhow!(
+            "{path} is encrypted but no encryption key is configured"
+        ))?;
+        let key_len = magic[MAGIC.len()] as usize;
+        let mut rest = vec![0; key_len + PREFIX_SIZE];
+        let n = read_chunk(&mut source.reader, &mut rest).await?;
+        source.read += n as u64;
+        anyhow::ensure!(n == rest.len(), "{path} has a truncated header");
+        let (key_id, prefix) = rest.split_at(key_len);
+        let key_id = String::from_utf8(key_id.to_vec())?;
+        let decryptor = cipher.decryptor(&key_id, prefix)?;
+        source.decryptor = Some((key_id, decryptor));
+        Ok(source)
+    }
+
+    /// key the archive was sealed with, none if it is plain
+    fn key_id(&self) -> Option<&str> {
+        self.decryptor.as_ref().map(|(key_id, _)| key_id.as_str())
+    }
+
+    async fn next(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        match &mut self.decryptor {
+            None => {
+                let mut buf = std::mem::take(&mut self.pending);
+                let filled = buf.len();
+                buf.resize(CHUNK_SIZE, 0);
+                let n = read_chunk(&mut self.reader, &mut buf[filled..]).await?;
+                self.read += n as u64;
+                buf.truncate(filled + n);
+                self.done = buf.len() < CHUNK_SIZE;
+                Ok((!buf.is_empty()).then_some(buf))
+            }
+            Some((_, decryptor)) => {
+                let mut buf = vec![0; CHUNK_SIZE + TAG_SIZE];
+                let n = read_chunk(&mut self.reader, &mut buf).await?;
+                self.read += n as u64;
+                self.done = n < buf.len();
+                Ok(Some(decryptor.open(&buf[..n], self.done)?))
+            }
+        }
+    }
+}
+
+/// stream `source` into every sink chunk by chunk, sealing chunks if `seal` is given.
+/// `total` is the stored size of the source. return sha256 of the plaintext. sinks failing on the way are recorded in them,
+/// a failing source fails the copy
+async fn stream_copy(
+    mut source: ChunkSource,
+    sinks: &mut [Sink],
+    seal: Option<(Vec<u8>, Encryptor)>,
+    total: u64,
+    progress: Progress<'_>,
+) -> anyhow::Result<String> {
+    let result = async {
+        // the header goes out with the first sealed chunk
+        let (mut header, mut encryptor) = match seal {
+            Some((header, encryptor)) => (header, Some(encryptor)),
+            None => (vec![], None),
+        };
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = source.next().await? {
+            hasher.update(&chunk);
+            let chunk = match &mut encryptor {
+                Some(encryptor) => {
+                    let mut sealed = std::mem::take(&mut header);
+                    sealed.extend(encryptor.seal(&chunk, chunk.len() < CHUNK_SIZE)?);
+                    sealed
+                }
+                None => chunk,
+            };
+            for sink in sinks.iter_mut() {
+                sink.write(&chunk).await;
+            }
+            progress(source.read, total);
+        }
+        // content of whole chunks still needs a last one
+        if let Some(encryptor) = &mut encryptor {
+            if !encryptor.is_finished() {
+                let mut chunk = std::mem::take(&mut header);
+                chunk.extend(encryptor.seal(&[], true)?);
+                for sink in sinks.iter_mut() {
+                    sink.write(&chunk).await;
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(hex::encode(hasher.finalize()))
+    }
+    .await;
+    match result {
+        Ok(checksum) => {
+            for sink in sinks.iter_mut() {
+                sink.close().await;
+            }
+            Ok(checksum)
+        }
+        Err(e) => {
+            for sink in sinks.iter_mut() {
+                sink.fail(format!("copy failed: {e}")).await;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// fill `buf` unless the reader ends first
+async fn read_chunk(reader: &mut SourceReader, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn checksum_path(path: &str) -> String {
+    format!("{path}.sha256")
+}
+
+/// checksum in the sidecar, none for saves kept before checksums
+async fn read_checksum(op: &Operator, path: &str) -> anyhow::Result<Option<String>> {
+    match op.read(&checksum_path(path)).await {
+        Ok(content) => Ok(Some(String::from_utf8(content)?.trim().to_string())),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound) => {
+            warn!("{path} has no checksum, not verified");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn check_checksum(path: &str, expected: &str, actual: &str) -> anyhow::Result<()> {
+    (expected == actual).then_some(()).ok_or(anyhow::anyhow!(
+        "checksum mismatch of {path}, expected {expected} got {actual}"
+    ))
+}
+
+fn build_fs(root: &str) -> anyhow::Result<Operator> {
+    let mut fs = Fs::default();
+    fs.root(root);
+    Ok(Operator::new(fs)?.finish())
+}
+
+/// where a replica keeps saves
+fn build_save_op(storage: &SaveStorageConfig) -> anyhow::Result<Operator> {
+    match storage {
+        SaveStorageConfig::Local(config) => build_fs(&config.local_dir),
+        SaveStorageConfig::S3(config) => {
+            let mut s3 = S3::default();
+            s3.root(&config.root)
+                .bucket(&config.bucket)
+                .endpoint(&config.endpoint)
+                .region(&config.region)
+                .access_key_id(&config.access_key_id)
+                .secret_access_key(&config.secret_access_key);
+            if config.virtual_host_style {
+                s3.enable_virtual_host_style();
+            }
+            Ok(Operator::new(s3)?.finish())
+        }
+        SaveStorageConfig::Webdav(config) => {
+            let mut webdav = Webdav::default();
+            webdav.root(&config.root).endpoint(&config.endpoint);
+            if let Some(username) = &config.username {
+                webdav.username(username);
+            }
+            if let Some(password) = &config.password {
+                webdav.password(password);
+            }
+            if let Some(token) = &config.token {
+                webdav.token(token);
+            }
+            Ok(Operator::new(webdav)?.finish())
+        }
+    }
+}
+
+fn is_presign(storage: &SaveStorageConfig) -> bool {
+    matches!(storage, SaveStorageConfig::S3(config) if config.presign)
+}
+
+/// saves of `server` in `op`, newest first
+async fn list_saves(op: &Operator, server: &str) -> anyhow::Result<Vec<SaveInfo>> {
+    let mut saves = vec![];
+    for entry in list_files(op, &format!("/saves/{server}/")).await? {
+        if !is_save_name(entry.name()) {
+            continue;
+        }
+        let meta = op.stat(entry.path()).await?;
+        saves.push(SaveInfo {
+            name: entry.name().to_string(),
+            size: meta.content_length(),
+        });
+    }
+    saves.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(saves)
+}
+
+/// remote pull commands print `sha256sum` output only after curl succeeded
+fn pulled_checksum(output: String) -> anyhow::Result<String> {
+    output
+        .split_whitespace()
+        .next()
+        .filter(|sum| sum.len() == 64 && sum.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(str::to_string)
+        .ok_or(anyhow::anyhow!(
+            "instance failed to transfer save: {output}"
+        ))
+}
+
+/// remote transfer commands echo `ok` after curl succeeded
+fn check_remote_transfer(output: String) -> anyhow::Result<()> {
+    (output.trim() == "ok").then_some(()).ok_or(anyhow::anyhow!(
+        "instance failed to transfer save: {output}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll},
+    };
+
+    use async_trait::async_trait;
+    use hyper::{
+        body::HttpBody,
+        header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, LAST_MODIFIED, RANGE},
+        Body, Method, Request, Response, StatusCode,
+    };
+    use tempfile::TempDir;
+    use tokio::io::ReadBuf;
+
+    use super::*;
+    use crate::{
+        shell_manager::{Cancel, Script, ScriptOutcome, ScriptProgress},
+        stand_in::serve,
+    };
+
+    /// passes reads through, keeping the largest buffer a read asked to fill
+    struct RecordingReader {
+        inner: SourceReader,
+        largest: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for RecordingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.largest.fetch_max(buf.remaining(), Ordering::SeqCst);
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    /// header of an archive sealed with `cipher()`
+    const HEADER: usize = MAGIC.len() + 1 + "k1".len() + PREFIX_SIZE;
+
+    /// no transfer reads, buffers or writes more than a sealed chunk with the header at once
+    const LARGEST_WRITE: usize = HEADER + CHUNK_SIZE + TAG_SIZE;
+
+    /// a `Fs` operator on a temp dir
+    struct TempFs {
+        dir: TempDir,
+        op: Operator,
+        /// largest read of any copy source
+        largest_read: Arc<AtomicUsize>,
+        /// largest write of any copy sink
+        largest_write: AtomicUsize,
+    }
+
+    impl TempFs {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let op = build_fs(dir.path().to_str().unwrap()).unwrap();
+            Self {
+                dir,
+                op,
+                largest_read: Arc::new(AtomicUsize::new(0)),
+                largest_write: AtomicUsize::new(0),
+            }
+        }
+
+        fn size(&self, path: &str) -> u64 {
+            std::fs::metadata(self.dir.path().join(path)).unwrap().len()
+        }
+
+        /// copy `from` to `to` like the transfers do, with every progress call
+        async fn copy(
+            &self,
+            from: &str,
+            to: &str,
+            cipher: Option<&SaveCipher>,
+            seal: bool,
+        ) -> (anyhow::Result<String>, Vec<(u64, u64)>) {
+            let calls = Mutex::new(vec![]);
+            let total = self.size(from);
+            let result = async {
+                let reader = RecordingReader {
+                    inner: Box::new(self.op.reader(from).await?),
+                    largest: self.largest_read.clone(),
+                };
+                let source = ChunkSource::open_reader(Box::new(reader), from, cipher).await?;
+                let mut sinks = vec![Sink::new(&self.op, to).await?];
+                let seal = cipher.filter(|_| seal).map(SaveCipher::encryptor);
+                let copied = stream_copy(source, &mut sinks, seal, total, &|done, total| {
+                    calls.lock().unwrap().push((done, total))
+                })
+                .await;
+                self.largest_write
+                    .fetch_max(sinks[0].largest_write, Ordering::SeqCst);
+                copied
+            }
+            .await;
+            (result, calls.into_inner().unwrap())
+        }
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn cipher() -> SaveCipher {
+        SaveCipher::new(&EncryptionConfig {
+            current: "k1".into(),
+            keys: HashMap::from([("k1".into(), "11".repeat(32))]),
+        })
+        .unwrap()
+    }
+
+    /// progress ends at `(total, total)` in steps no larger than a sealed chunk
+    fn check_progress(calls: &[(u64, u64)], total: u64) {
+        assert_eq!(calls.last(), Some(&(total, total)));
+        // the first step also covers the header of a sealed source
+        let mut last = HEADER as u64;
+        for &(done, _) in calls {
+            assert!(done.saturating_sub(last) <= (CHUNK_SIZE + TAG_SIZE) as u64);
+            last = done;
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_copy_of_chunks() {
+        let fs = TempFs::new();
+        let data = content(CHUNK_SIZE * 2 + 12345);
+        fs.op.write("plain", data.clone()).await.unwrap();
+
+        let (checksum, calls) = fs.copy("plain", "copy", None, false).await;
+        assert_eq!(checksum.unwrap(), hex::encode(Sha256::digest(&data)));
+        assert_eq!(fs.op.read("copy").await.unwrap(), data);
+        assert_eq!(calls.len(), 3);
+        check_progress(&calls, data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_are_bounded() {
+        let cipher = cipher();
+        let fs = TempFs::new();
+        let data = content(CHUNK_SIZE * 4 + 12345);
+        fs.op.write("plain", data.clone()).await.unwrap();
+
+        fs.copy("plain", "sealed", Some(&cipher), true)
+            .await
+            .0
+            .unwrap();
+        assert!(fs.largest_read.load(Ordering::SeqCst) <= CHUNK_SIZE);
+        let (opened, _) = fs.copy("sealed", "opened", Some(&cipher), false).await;
+        assert_eq!(opened.unwrap(), hex::encode(Sha256::digest(&data)));
+        // a sealed chunk and its tag at most, never the whole archive
+        assert!(fs.largest_read.load(Ordering::SeqCst) <= CHUNK_SIZE + TAG_SIZE);
+        let largest_write = fs.largest_write.load(Ordering::SeqCst);
+        assert!(largest_write >= CHUNK_SIZE && largest_write <= LARGEST_WRITE);
+    }
+
+    /// copy a sealed multi chunk save to a real sftp host and back. needs a host taking
+    /// `PSM_TEST_SSH_KEY` for user `ubuntu`:
+    /// `PSM_TEST_SSH_HOST=1.2.3.4 PSM_TEST_SSH_KEY=~/.ssh/id_ed25519 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn sftp_writes_are_bounded() {
+        let host = std::env::var("PSM_TEST_SSH_HOST").expect("PSM_TEST_SSH_HOST");
+        let key = std::env::var("PSM_TEST_SSH_KEY").expect("PSM_TEST_SSH_KEY");
+        use crate::shell_manager::{RemoteShell, ShellManager};
+        let shell = ShellManager::new(crate::config::SshConfig {
+            prikey: key,
+            user: "ubuntu".into(),
+        });
+        let sftp = shell.files(&host, "/tmp/psm-test/").unwrap();
+        let cipher = cipher();
+        let fs = TempFs::new();
+        let data = content(CHUNK_SIZE * 2 + 12345);
+        fs.op.write("plain", data.clone()).await.unwrap();
+
+        let source = ChunkSource::raw(&fs.op, "plain").await.unwrap();
+        let mut sinks = vec![Sink::new(&sftp, "sealed").await.unwrap()];
+        let checksum = stream_copy(
+            source,
+            &mut sinks,
+            Some(cipher.encryptor()),
+            data.len() as u64,
+            &|_, _| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(sinks[0].error, None);
+        assert!(sinks[0].largest_write <= LARGEST_WRITE);
+
+        let source = ChunkSource::open(&sftp, "sealed", Some(&cipher))
+            .await
+            .unwrap();
+        let mut sinks = vec![Sink::new(&fs.op, "opened").await.unwrap()];
+        let total = sftp.stat("sealed").await.unwrap().content_length();
+        let opened = stream_copy(source, &mut sinks, None, total, &|_, _| {})
+            .await
+            .unwrap();
+        sftp.delete("sealed").await.unwrap();
+        assert_eq!(opened, checksum);
+        assert_eq!(fs.op.read("opened").await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn sealed_round_trip() {
+        let cipher = cipher();
+        // a short last chunk, and whole chunks followed by an empty last one
+        for len in [CHUNK_SIZE * 2 + 12345, CHUNK_SIZE * 2] {
+            let fs = TempFs::new();
+            let data = content(len);
+            fs.op.write("plain", data.clone()).await.unwrap();
+            let checksum = hex::encode(Sha256::digest(&data));
+
+            let (sealed, calls) = fs.copy("plain", "sealed", Some(&cipher), true).await;
+            assert_eq!(sealed.unwrap(), checksum);
+            check_progress(&calls, len as u64);
+            // every chunk is sealed on its own, including the last one
+            let chunks = len / CHUNK_SIZE + 1;
+            assert_eq!(fs.size("sealed"), (HEADER + len + chunks * TAG_SIZE) as u64);
+
+            let (opened, calls) = fs.copy("sealed", "opened", Some(&cipher), false).await;
+            assert_eq!(opened.unwrap(), checksum);
+            check_progress(&calls, fs.size("sealed"));
+            assert_eq!(fs.op.read("opened").await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_sealed_archive_fails() {
+        let cipher = cipher();
+        let fs = TempFs::new();
+        fs.op.write("plain", content(CHUNK_SIZE * 2)).await.unwrap();
+        fs.copy("plain", "sealed", Some(&cipher), true)
+            .await
+            .0
+            .unwrap();
+        let sealed = fs.op.read("sealed").await.unwrap();
+
+        // cut at a chunk boundary, in a chunk and before the empty last chunk
+        for len in [
+            HEADER + CHUNK_SIZE + TAG_SIZE,
+            HEADER + CHUNK_SIZE,
+            sealed.len() - TAG_SIZE,
+        ] {
+            fs.op.write("cut", sealed[..len].to_vec()).await.unwrap();
+            let (result, _) = fs.copy("cut", "opened", Some(&cipher), false).await;
+            assert!(result.is_err(), "truncated to {len} opened");
+        }
+    }
+
+    /// storage replicas from yaml, `{dir}` is replaced by `dir`
+    fn replicas(yaml: &str, dir: &TempDir) -> Vec<SaveStorageConfig> {
+        serde_yaml::from_str(&yaml.replace("{dir}", dir.path().to_str().unwrap())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unreachable_secondary_is_reported() {
+        let (primary, source) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        // the s3 replica can't even be built without a bucket
+        let storage = LocalStorage::new(replicas(
+            r#"
+- local: { local_dir: "{dir}", remote_dir: /home/psm/psm }
+- s3:
+    local_dir: /unused
+    remote_dir: /home/psm/psm
+    bucket: ""
+    endpoint: http://127.0.0.1:1
+    access_key_id: ak
+    secret_access_key: sk
+"#,
+            &primary,
+        ))
+        .unwrap();
+        let archive = source.path().join("Saved.20240101120000.tar.gz");
+        std::fs::write(&archive, content(1024)).unwrap();
+
+        let (save_name, reports) = storage.import_save("pal", &archive).await.unwrap();
+        assert!(reports[0].error.is_none());
+        assert!(reports[1].error.is_some());
+        assert!(storage.has_save("pal", &save_name).await.unwrap());
+
+        // the primary must keep it though, a file is no dir to keep saves in
+        let storage = LocalStorage::new(
+            serde_yaml::from_str(&format!(
+                "- local: {{ local_dir: \"{}\", remote_dir: /home/psm/psm }}",
+                archive.display()
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(storage.import_save("pal", &archive).await.is_err());
+    }
+
+    /// an in-memory webdav share answering what opendal's webdav writer sends
+    #[derive(Clone, Default)]
+    struct WebdavStandIn {
+        dirs: Arc<Mutex<HashSet<String>>>,
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        /// path of every PUT, in order
+        puts: Arc<Mutex<Vec<String>>>,
+        /// largest piece of a PUT body received at once
+        largest_frame: Arc<AtomicUsize>,
+    }
+
+    impl WebdavStandIn {
+        fn serve(&self) -> String {
+            serve(self.clone(), Self::handle)
+        }
+
+        async fn handle(self, req: Request<Body>) -> Response<Body> {
+            let method = req.method().clone();
+            let path = req.uri().path().trim_end_matches('/').to_string();
+            let (status, body) = match method.as_str() {
+                "PROPFIND" if path.is_empty() || self.dirs.lock().unwrap().contains(&path) => (
+                    StatusCode::MULTI_STATUS,
+                    format!(
+                        r#"<?xml version="1.0" encoding="utf-8"?><D:multistatus xmlns:D="DAV:"><D:response><D:href>{path}/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response></D:multistatus>"#
+                    ),
+                ),
+                "PROPFIND" => (StatusCode::NOT_FOUND, String::new()),
+                "MKCOL" => {
+                    self.dirs.lock().unwrap().insert(path);
+                    (StatusCode::CREATED, String::new())
+                }
+                _ if method == Method::PUT => {
+                    let mut body = req.into_body();
+                    let mut content = vec![];
+                    while let Some(frame) = body.data().await {
+                        let frame = frame.unwrap();
+                        self.largest_frame.fetch_max(frame.len(), Ordering::SeqCst);
+                        content.extend_from_slice(&frame);
+                    }
+                    self.puts.lock().unwrap().push(path.clone());
+                    self.files.lock().unwrap().insert(path, content);
+                    (StatusCode::CREATED, String::new())
+                }
+                _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+            };
+            Response::builder()
+                .status(status)
+                .body(Body::from(body))
+                .unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn sealed_save_is_one_streamed_write_to_webdav() {
+        let share = WebdavStandIn::default();
+        let endpoint = share.serve();
+        let (primary, source) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let storage = LocalStorage::new(replicas(
+            &format!(
+                r#"
+- local:
+    local_dir: "{{dir}}"
+    remote_dir: /home/psm/psm
+    encryption: {{ current: k1, keys: {{ k1: "{key}" }} }}
+- webdav: {{ local_dir: /unused, remote_dir: /home/psm/psm, endpoint: "{endpoint}" }}
+"#,
+                key = "11".repeat(32)
+            ),
+            &primary,
+        ))
+        .unwrap();
+        // more than one chunk, each sealed chunk used to be a write of its own
+        let archive = source.path().join("Saved.20240101120000.tar.gz");
+        std::fs::write(&archive, content(CHUNK_SIZE * 2 + 12345)).unwrap();
+
+        let (save_name, reports) = storage.import_save("pal", &archive).await.unwrap();
+        for report in &reports {
+            assert!(report.error.is_none(), "{report}");
+        }
+        let path = save_path("pal", &save_name);
+        assert_eq!(
+            *share.puts.lock().unwrap(),
+            [path.clone(), checksum_path(&path)]
+        );
+        // the same sealed bytes as the primary keeps
+        let kept = std::fs::read(primary.path().join(path.trim_start_matches('/'))).unwrap();
+        assert!(kept.starts_with(MAGIC));
+        assert_eq!(share.files.lock().unwrap()[&path], kept);
+        // streamed from the spool, never sent from memory whole
+        assert!(share.largest_frame.load(Ordering::SeqCst) <= LARGEST_WRITE);
+    }
+
+    /// parts of a multipart upload by part number
+    type Parts = BTreeMap<u32, Vec<u8>>;
+
+    /// an in-memory s3 bucket answering what opendal's s3 backend and the presigned curls of
+    /// an instance send, signatures are not checked
+    #[derive(Clone, Default)]
+    struct S3StandIn {
+        /// objects by request path, `/<bucket>/<key>`
+        objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        /// parts of multipart uploads in progress, by upload id
+        uploads: Arc<Mutex<HashMap<String, Parts>>>,
+        next_upload: Arc<AtomicUsize>,
+        /// path of every completed multipart upload
+        multipart: Arc<Mutex<Vec<String>>>,
+        /// method and path of every request through a presigned url
+        presigned: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl S3StandIn {
+        fn serve(&self) -> String {
+            serve(self.clone(), Self::handle)
+        }
+
+        async fn handle(self, req: Request<Body>) -> Response<Body> {
+            let path = req.uri().path().to_string();
+            let query: HashMap<String, String> = req
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (key.to_string(), value.to_string())
+                })
+                .collect();
+            let key = path.trim_start_matches("/psm/").to_string();
+            let method = req.method().clone();
+            if query.contains_key("X-Amz-Signature") {
+                self.presigned
+                    .lock()
+                    .unwrap()
+                    .push(format!("{method} {path}"));
+            }
+            let range = req
+                .headers()
+                .get(RANGE)
+                .and_then(|range| range.to_str().ok()?.strip_prefix("bytes="))
+                .map(str::to_string);
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let response = Response::builder();
+            let etag =
+                |content: &[u8]| format!("\"{}\"", &hex::encode(Sha256::digest(content))[..32]);
+            let response = match (method, query.get("uploadId")) {
+                (Method::POST, None) if query.contains_key("uploads") => {
+                    let id = format!("upload{}", self.next_upload.fetch_add(1, Ordering::SeqCst));
+                    self.uploads
+                        .lock()
+                        .unwrap()
+                        .insert(id.clone(), BTreeMap::new());
+                    response.body(Body::from(format!(
+                        "<InitiateMultipartUploadResult><Bucket>psm</Bucket><Key>{key}</Key><UploadId>{id}</UploadId></InitiateMultipartUploadResult>"
+                    )))
+                }
+                (Method::PUT, Some(id)) => {
+                    let part = query["partNumber"].parse().unwrap();
+                    let tag = etag(&body);
+                    match self.uploads.lock().unwrap().get_mut(id) {
+                        Some(parts) => {
+                            parts.insert(part, body.to_vec());
+                            response.header(ETAG, tag).body(Body::empty())
+                        }
+                        None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+                    }
+                }
+                (Method::POST, Some(id)) => match self.uploads.lock().unwrap().remove(id) {
+                    Some(parts) => {
+                        let content = parts.into_values().flatten().collect::<Vec<_>>();
+                        let tag = etag(&content);
+                        self.objects.lock().unwrap().insert(path.clone(), content);
+                        self.multipart.lock().unwrap().push(path.clone());
+                        response.body(Body::from(format!(
+                            "<CompleteMultipartUploadResult><Bucket>psm</Bucket><Key>{key}</Key><ETag>{tag}</ETag></CompleteMultipartUploadResult>"
+                        )))
+                    }
+                    None => response.status(StatusCode::NOT_FOUND).body(Body::empty()),
+                },
+                (Method::DELETE, Some(id)) => {
+                    self.uploads.lock().unwrap().remove(id);
+                    response.status(StatusCode::NO_CONTENT).body(Body::empty())
+                }
+                (Method::PUT, None) => {
+                    let tag = etag(&body);
+                    self.objects.lock().unwrap().insert(path, body.to_vec());
+                    response.header(ETAG, tag).body(Body::empty())
+                }
+                (Method::DELETE, None) => {
+                    self.objects.lock().unwrap().remove(&path);
+                    response.status(StatusCode::NO_CONTENT).body(Body::empty())
+                }
+                (method, None) if method == Method::GET || method == Method::HEAD => {
+                    let Some(content) = self.objects.lock().unwrap().get(&path).cloned() else {
+                        return response
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("<Error><Code>NoSuchKey</Code></Error>"))
+                            .unwrap();
+                    };
+                    let response = response
+                        .header(ETAG, etag(&content))
+                        .header(LAST_MODIFIED, "Mon, 01 Jan 2024 12:00:00 GMT");
+                    if method == Method::HEAD {
+                        response
+                            .header(CONTENT_LENGTH, content.len())
+                            .body(Body::empty())
+                    } else if let Some((start, end)) =
+                        range.as_ref().and_then(|r| r.split_once('-'))
+                    {
+                        let len = content.len();
+                        let end = end.parse().map_or(len, |end: usize| (end + 1).min(len));
+                        let start = start.parse().unwrap_or(0).min(end);
+                        response
+                            .status(StatusCode::PARTIAL_CONTENT)
+                            .header(
+                                CONTENT_RANGE,
+                                format!("bytes {start}-{}/{len}", end.max(1) - 1),
+                            )
+                            .body(Body::from(content[start..end].to_vec()))
+                    } else {
+                        response.body(Body::from(content))
+                    }
+                }
+                _ => response
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty()),
+            };
+            response.unwrap()
+        }
+    }
+
+    /// an instance whose files are local, commands run in a local shell
+    struct LocalShell;
+
+    #[async_trait]
+    impl RemoteShell for LocalShell {
+        async fn exec(&self, _ip: &str, command: &str) -> anyhow::Result<String> {
+            let output = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .output()
+                .await?;
+            Ok(String::from_utf8(output.stdout)?)
+        }
+
+        async fn run(
+            &self,
+            _ip: &str,
+            script: Script,
+            _progress: &(dyn Fn(ScriptProgress) + Send + Sync),
+            _cancel: &Cancel,
+        ) -> anyhow::Result<ScriptOutcome> {
+            anyhow::bail!("local shell can't run {script:?}")
+        }
+
+        fn files(&self, _ip: &str, root: &str) -> anyhow::Result<Operator> {
+            build_fs(root)
+        }
+    }
+
+    /// back up a multi chunk save from the instance to an s3 primary, then restore it to the
+    /// instance. return the bucket and the bucket path of the save
+    async fn s3_round_trip(presign: bool) -> (S3StandIn, String) {
+        let bucket = S3StandIn::default();
+        let endpoint = bucket.serve();
+        let instance = TempDir::new().unwrap();
+        let storage = LocalStorage::new(replicas(
+            &format!(
+                r#"
+- s3:
+    local_dir: /unused
+    remote_dir: "{{dir}}"
+    bucket: psm
+    endpoint: "{endpoint}"
+    access_key_id: ak
+    secret_access_key: sk
+    presign: {presign}
+"#
+            ),
+            &instance,
+        ))
+        .unwrap();
+        let save_name = "Saved.20240101120000.tar.gz";
+        let path = save_path("pal", save_name);
+        let on_instance = instance.path().join(path.trim_start_matches('/'));
+        let data = content(CHUNK_SIZE * 2 + 12345);
+        std::fs::create_dir_all(on_instance.parent().unwrap()).unwrap();
+        std::fs::write(&on_instance, &data).unwrap();
+        let checksum = hex::encode(Sha256::digest(&data));
+
+        let reports = storage
+            .download_saves(
+                "pal",
+                save_name,
+                &checksum,
+                &LocalShell,
+                "127.0.0.1",
+                &|_, _| {},
+            )
+            .await
+            .unwrap();
+        assert!(reports[0].error.is_none(), "{}", reports[0]);
+        let in_bucket = format!("/psm{path}");
+        {
+            let objects = bucket.objects.lock().unwrap();
+            assert_eq!(objects[&in_bucket], data);
+            assert_eq!(objects[&checksum_path(&in_bucket)], checksum.as_bytes());
+        }
+
+        std::fs::remove_file(&on_instance).unwrap();
+        let (replica, pulled) = storage
+            .upload_saves("pal", save_name, &LocalShell, "127.0.0.1", &|_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(replica, "s3(primary)");
+        assert_eq!(pulled, checksum);
+        assert_eq!(std::fs::read(&on_instance).unwrap(), data);
+        (bucket, in_bucket)
+    }
+
+    #[tokio::test]
+    async fn s3_relay_round_trip() {
+        let (bucket, path) = s3_round_trip(false).await;
+        // relayed by the manager in multipart parts, the instance never touches the bucket
+        assert_eq!(*bucket.multipart.lock().unwrap(), [path]);
+        assert!(bucket.presigned.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn s3_presigned_round_trip() {
+        let (bucket, path) = s3_round_trip(true).await;
+        // the instance puts and gets the save itself
+        assert_eq!(
+            *bucket.presigned.lock().unwrap(),
+            [format!("PUT {path}"), format!("GET {path}")]
+        );
+        assert!(bucket.multipart.lock().unwrap().is_empty());
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn keep_last_keeps_newest() {
+        let retention = RetentionConfig {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let saves = names(&[
+            "Saved.20240101120000.tar.gz",
+            "Saved.20240103120000.tar.gz",
+            "Saved.20240102120000.tar.gz",
+        ]);
+        assert_eq!(
+            retention.expired(&saves),
+            names(&["Saved.20240101120000.tar.gz"])
+        );
+    }
+
+    #[test]
+    fn daily_and_weekly_overlap() {
+        // the newest of each day within 2 days, and of each iso week within 2 weeks
+        let retention = RetentionConfig {
+            keep_daily: Some(2),
+            keep_weekly: Some(2),
+            ..Default::default()
+        };
+        let saves = names(&[
+            // wednesday, newest of the day and of the week
+            "Saved.20240110180000.tar.gz",
+            "Saved.20240110060000.tar.gz",
+            // tuesday, newest of the day only
+            "Saved.20240109120000.tar.gz",
+            // sunday of the previous week, newest of that week but older than 2 days
+            "Saved.20240107120000.tar.gz",
+            "Saved.20240106120000.tar.gz",
+            // more than 2 weeks older
+            "Saved.20231220120000.tar.gz",
+        ]);
+        let mut expired = retention.expired(&saves);
+        expired.sort();
+        assert_eq!(
+            expired,
+            names(&[
+                "Saved.20231220120000.tar.gz",
+                "Saved.20240106120000.tar.gz",
+                "Saved.20240110060000.tar.gz",
+            ])
+        );
+    }
+
+    #[test]
+    fn unparseable_names_are_kept() {
+        let retention = RetentionConfig {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let saves = names(&[
+            "Saved.20240102120000.tar.gz",
+            "Saved.20240101120000.tar.gz",
+            "Saved.tar.gz",
+            "Saved.2024-01-01.tar.gz",
+            "world.zip",
+        ]);
+        assert_eq!(
+            retention.expired(&saves),
+            names(&["Saved.20240101120000.tar.gz"])
+        );
+    }
+
+    #[test]
+    fn keep_last_zero_alone_is_rejected() {
+        let zero = RetentionConfig {
+            keep_last: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.check().is_err());
+        let with_daily = RetentionConfig {
+            keep_daily: Some(7),
+            ..zero
+        };
+        assert!(with_daily.check().is_ok());
+        assert!(RetentionConfig::default().check().is_ok());
+    }
+
+    #[test]
+    fn failed_pull_has_no_checksum() {
+        let sum = "a".repeat(64);
+        assert_eq!(
+            pulled_checksum(format!("{sum}  /home/psm/saves/pal/Saved.tar.gz\n")).unwrap(),
+            sum
+        );
+        assert!(pulled_checksum(String::new()).is_err());
+        assert!(pulled_checksum("curl: (22) 403\n".into()).is_err());
+    }
+}
@@ -1,15 +1,24 @@
 pub(crate) mod bot_cmd;
+pub(crate) mod csp;
 pub(crate) mod cvm_utils;
 pub(crate) mod error;
 pub(crate) mod local_storage;
+pub(crate) mod save_crypto;
+pub(crate) mod save_import;
 pub(crate) mod server_status;
 pub(crate) mod shell_manager;
 
 mod config;
 mod constant;
 mod psm;
+#[cfg(test)]
+mod stand_in;
 
-use crate::psm::PalServiceManager;
+use crate::{
+    local_storage::LocalStorage,
+    psm::PalServiceManager,
+    server_status::{ServerManager, Status},
+};
 
 use clap::Parser;
 use std::path::Path;
@@ -35,6 +44,22 @@ struct Args {
     /// enable debug log
     #[clap(long)]
     debug: bool,
+
+    /// encrypt stored saves with the current key then exit, for rotating keys
+    #[clap(long)]
+    reencrypt_saves: bool,
+
+    /// import a zip or tar of a local game Saved dir then exit
+    #[clap(long, value_name = "Archive", requires = "import_to")]
+    import_save: Option<String>,
+
+    /// server the imported save is registered to, the server and the manager must be stopped
+    #[clap(long, value_name = "Save Name", requires = "import_save")]
+    import_to: Option<String>,
+
+    /// move the co-op host player of the imported save to the guid a dedicated server gave them
+    #[clap(long, value_name = "Guid", requires = "import_save")]
+    host_guid: Option<String>,
 }
 
 #[tokio::main]
@@ -52,6 +77,33 @@ async fn main() -> anyhow::Result<()> {
     let server_status_path_str = args.server.unwrap_or("./server_status.yaml".into());
     let server_status_path = Path::new(&server_status_path_str);
     let _g = file_log(log_path, args.debug)?;
+    // held until exit, so cli imports never race a running manager
+    let _status_lock = server_status::lock_status_file(server_status_path)?;
+    if args.reencrypt_saves {
+        let servers: Vec<_> = ServerManager::new(server_status_path)
+            .save_owners()
+            .into_iter()
+            .map(|(server, _)| server)
+            .collect();
+        let storage = LocalStorage::new(config.storage.replicas())?;
+        let count = storage.reencrypt_saves(&servers).await?;
+        println!("re-encrypted {count} saves");
+        return Ok(());
+    }
+    if let Some((archive, server)) = args.import_save.zip(args.import_to) {
+        let mut servers = ServerManager::new(server_status_path);
+        servers.check_server_status(&server, &Status::Stopped)?;
+        let save =
+            save_import::normalize_save(Path::new(&archive), args.host_guid.as_deref()).await?;
+        let storage = LocalStorage::new(config.storage.replicas())?;
+        let (save_name, reports) = storage.import_save(&server, &save.archive).await?;
+        servers.update_save_name(&server, &save_name)?;
+        println!("imported {save_name} to {server}");
+        for report in reports {
+            println!("  {report}");
+        }
+        return Ok(());
+    }
     println!("---- start Pal Service Manager ----");
     let psm = PalServiceManager::new(config, server_status_path).await;
     psm.start().await;
@@ -1,55 +1,82 @@
-use std::{fmt::Display, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Timelike;
+use itertools::Itertools;
 
 use async_trait::async_trait;
 use cqhttp_bot_frame::{
     bot::{Bot, Handler},
     RecvMsg, SendMsg,
 };
-use tencentcloud_sdk::{client::TencentCloudClient, constant::Region};
-use tokio::sync::{mpsc::Sender, Mutex};
+use tokio::sync::{mpsc::Sender, watch, Mutex};
 use tracing::{debug, error, info};
 
 use crate::{
-    bot_cmd::Commands,
-    config::{CSPConfig, PsmConfig, SaveStorageConfig},
+    bot_cmd::{Commands, ServerArgs},
+    config::{NotifyConfig, PlacementConfig, PsmConfig},
     constant::ServiceInstanceType,
-    cvm_utils::{query_cvm_ip, query_key_ids, query_spot_paid_price},
+    csp::{Candidate, CloudProvider, CloudProviders},
     error::PSMError,
     local_storage::LocalStorage,
     server_status::{ServerManager, Status},
-    shell_manager::{Script, ShellManager},
+    shell_manager::{
+        no_cancel, Cancel, RemoteShell, Script, ScriptProgress, ScriptResult, ShellManager,
+    },
 };
 
 pub struct PalServiceManager {
     _bot_send_tx: Arc<Sender<SendMsg>>, // might be useless
+    task_handler: Arc<PalTaskHandler>,
 }
 
 impl PalServiceManager {
     pub async fn new(config: PsmConfig, server_status_path: &std::path::Path) -> Self {
-        // need ref
-        let CSPConfig::TencentCloud(csp_config) = config.csp.clone();
-        let client = Arc::new(TencentCloudClient::new(&csp_config));
+        let providers = Arc::new(CloudProviders::new(&config.csp.providers()));
 
-        let server_status_manager = Arc::new(Mutex::new(ServerManager::new(server_status_path)));
+        let server_status_manager = ServerManager::new(server_status_path);
+        server_status_manager
+            .check_regions(|r| providers.is_known_region(r))
+            .expect("invalid regions in server status file");
+        let server_status_manager = Arc::new(Mutex::new(server_status_manager));
         let shell_manager = Arc::new(ShellManager::new(config.ssh.clone()));
 
-        // need_ref
-        let SaveStorageConfig::Local(storage_config) = config.storage.clone();
-        let local_storage = Arc::new(LocalStorage::new(storage_config));
+        let local_storage =
+            Arc::new(LocalStorage::new(config.storage.replicas()).expect("invalid storage config"));
+        local_storage
+            .migrate_saves(&server_status_manager.lock().await.save_owners())
+            .await
+            .expect("migrate saves to per server directories failed");
 
         let (instant_tx, instant_rx) = tokio::sync::mpsc::channel::<SendMsg>(10);
         let bot_send_tx = Arc::new(instant_tx); // maybe useless...
         let task_handler = Arc::new(PalTaskHandler::new(
-            client,
+            providers,
             bot_send_tx.clone(),
             server_status_manager,
             shell_manager,
             local_storage,
             Arc::new(config.clone()),
         ));
+        for server in task_handler
+            .server_status_manager
+            .lock()
+            .await
+            .running_servers()
+        {
+            task_handler.spawn_reclaim_watcher(server);
+        }
 
         if let Some(bot_config) = config.bot {
-            let bot = Bot::new(bot_config, task_handler, instant_rx).await;
+            let bot = Bot::new(bot_config, task_handler.clone(), instant_rx).await;
             tokio::spawn(async move {
                 bot.start().await;
             });
@@ -57,46 +84,210 @@ impl PalServiceManager {
 
         Self {
             _bot_send_tx: bot_send_tx,
+            task_handler,
         }
     }
 
     pub async fn start(&self) -> ! {
+        // last backup of each running server, the first one is due an interval after seen running
+        let mut last_backup = HashMap::<String, Instant>::new();
         loop {
-            tokio::time::sleep(Duration::from_secs(10)).await;
+            tokio::time::sleep(Duration::from_secs(BACKUP_CHECK_INTERVAL)).await;
+            let running = self
+                .task_handler
+                .server_status_manager
+                .lock()
+                .await
+                .running_servers();
+            last_backup.retain(|server, _| running.contains(server));
+            for server in running {
+                let last = *last_backup
+                    .entry(server.clone())
+                    .or_insert_with(Instant::now);
+                if self.task_handler.backup_due(&server, last).await {
+                    last_backup.insert(server.clone(), Instant::now());
+                    self.task_handler.periodic_backup(&server).await;
+                }
+            }
         }
     }
 }
 
+/// seconds between checks for due periodic backups
+const BACKUP_CHECK_INTERVAL: u64 = 60;
+
+/// re-query prices at most this many times, since stock changes while walking candidates
+const MAX_QUERY_ROUNDS: usize = 3;
+
+/// only saves larger than this report transfer progress
+const PROGRESS_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// seconds between script progress percentages sent to the chat
+const SCRIPT_PROGRESS_INTERVAL: u64 = 30;
+
+/// seconds between spot instance reclaim checks
+const RECLAIM_CHECK_INTERVAL: u64 = 30;
+
+#[derive(Clone)]
+/// a candidate that could not be created
+enum CreateError {
+    /// nothing of it is left running, the next candidate can be tried
+    Failed(String),
+    /// its instance could not be terminated and stays recorded, the start stops to clean it up
+    Leaked(String),
+}
+
 struct PalTaskHandler {
-    pub(crate) client: Arc<TencentCloudClient>, // todo ref to Arc<dyn CSP>
+    pub(crate) providers: Arc<CloudProviders>,
     pub(crate) bot_instant_tx: Arc<Sender<SendMsg>>,
     pub(crate) server_status_manager: Arc<Mutex<ServerManager>>,
-    pub(crate) shell_manager: Arc<ShellManager>,
+    pub(crate) shell_manager: Arc<dyn RemoteShell>,
     pub(crate) local_storage: Arc<LocalStorage>,
     pub(crate) config: Arc<PsmConfig>,
+    /// last cmd msg of each server, background tasks reply to it
+    pub(crate) reply_to: Arc<Mutex<HashMap<String, RecvMsg>>>,
+    /// servers being started, `--cancel` sends through it
+    pub(crate) starting: Arc<Mutex<HashMap<String, watch::Sender<bool>>>>,
+    /// held while backing up, stopping or rescuing a server, periodic backups skip busy ones
+    pub(crate) save_locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
 }
 
 impl PalTaskHandler {
     pub fn new(
-        client: Arc<TencentCloudClient>,
+        providers: Arc<CloudProviders>,
         bot_instant_tx: Arc<Sender<SendMsg>>,
         server_status_manager: Arc<Mutex<ServerManager>>,
-        shell_manager: Arc<ShellManager>,
+        shell_manager: Arc<dyn RemoteShell>,
         local_storage: Arc<LocalStorage>,
         config: Arc<PsmConfig>,
     ) -> Self {
         Self {
-            client,
+            providers,
             bot_instant_tx,
             server_status_manager,
             shell_manager,
             local_storage,
             config,
+            reply_to: Arc::new(Mutex::new(HashMap::new())),
+            starting: Arc::new(Mutex::new(HashMap::new())),
+            save_locks: Arc::new(Mutex::new(HashMap::new())),
         }
     }
     fn err_log(e: impl Display) {
         error!("PalTaskHandler ERROR :{e}");
     }
+    /// message the group last operating `server`, or the configured notify group
+    async fn notify(&self, server: &str, content: String) {
+        info!("notify {server}: {content}");
+        let msg = self.reply_to.lock().await.get(server).cloned();
+        match (msg, &self.config.notify) {
+            (Some(msg), _) => self.say(Some(&msg), content).await,
+            (None, Some(target)) => send_group_msg(target, &content)
+                .await
+                .unwrap_or_else(Self::err_log),
+            (None, None) => debug!("no group to notify: {content}"),
+        }
+    }
+    /// reply to `msg`, operations without a bot message only log
+    async fn say(&self, msg: Option<&RecvMsg>, content: String) {
+        match msg {
+            Some(msg) => self
+                .bot_instant_tx
+                .send(msg.reply(content))
+                .await
+                .unwrap_or_else(Self::err_log),
+            None => debug!("no bot message to reply: {content}"),
+        }
+    }
+    /// report progress of large save transfers to `msg` every quarter
+    fn transfer_progress(
+        &self,
+        msg: Option<RecvMsg>,
+        action: String,
+    ) -> impl Fn(u64, u64) + Send + Sync {
+        let bot_instant_tx = self.bot_instant_tx.clone();
+        let reported = AtomicU64::new(0);
+        move |done, total| {
+            let Some(msg) = &msg else {
+                return;
+            };
+            if total < PROGRESS_MIN_SIZE {
+                return;
+            }
+            let quarter = done * 4 / total;
+            if quarter > reported.fetch_max(quarter, Ordering::SeqCst) {
+                bot_instant_tx
+                    .try_send(msg.reply(format!("{action}: {}%", quarter * 25)))
+                    .unwrap_or_else(Self::err_log);
+            }
+        }
+    }
+
+    /// forward notable script output, percentages at most every `SCRIPT_PROGRESS_INTERVAL`
+    fn script_progress(
+        &self,
+        msg: Option<RecvMsg>,
+        action: &'static str,
+    ) -> impl Fn(ScriptProgress) + Send + Sync {
+        let bot_instant_tx = self.bot_instant_tx.clone();
+        let last_percent = std::sync::Mutex::new(None::<Instant>);
+        move |progress| {
+            let Some(msg) = &msg else {
+                return;
+            };
+            let content = match progress {
+                ScriptProgress::Percent(stage, percent) => {
+                    let mut last = last_percent.lock().expect("not poisoned");
+                    if last.is_some_and(|last| {
+                        last.elapsed() < Duration::from_secs(SCRIPT_PROGRESS_INTERVAL)
+                    }) {
+                        return;
+                    }
+                    *last = Some(Instant::now());
+                    format!("{action}: {stage} {percent:.0}%")
+                }
+                ScriptProgress::Notice(line) => format!("{action}: {line}"),
+            };
+            bot_instant_tx
+                .try_send(msg.reply(content))
+                .unwrap_or_else(Self::err_log);
+        }
+    }
+
+    async fn save_lock(&self, server: &str) -> Arc<Mutex<()>> {
+        self.save_locks
+            .lock()
+            .await
+            .entry(server.to_string())
+            .or_default()
+            .clone()
+    }
+    /// wait for `fut` unless the start is cancelled first
+    async fn until_cancelled<T>(
+        cancel: &Cancel,
+        fut: impl Future<Output = Result<T, PSMError>>,
+    ) -> Result<T, PSMError> {
+        let mut cancel = cancel.clone();
+        tokio::select! {
+            res = fut => res,
+            Ok(_) = cancel.wait_for(|cancelled| *cancelled) => Err(anyhow::anyhow!("cancelled").into()),
+        }
+    }
+
+    async fn cancel_start(&self, server: String, msg: &RecvMsg) {
+        let content = match self.starting.lock().await.get(&server) {
+            Some(cancel) => {
+                cancel.send_replace(true);
+                format!("Cancelling start of {server}")
+            }
+            None => format!("{server} is not starting"),
+        };
+        self.bot_instant_tx
+            .send(msg.reply(content))
+            .await
+            .unwrap_or_else(Self::err_log);
+    }
+
     async fn list_server(&self, server: String, msg: &RecvMsg) {
         let content = match self.server_status_manager.lock().await.list(&server) {
             Ok(result) => result,
@@ -108,140 +299,339 @@ impl PalTaskHandler {
             .unwrap_or_else(Self::err_log);
     }
 
-    async fn query_and_create_server(
-        &self,
-        candidate_regions: &[Region],
-        instance_type: ServiceInstanceType,
-        msg: &RecvMsg,
-    ) -> Result<(String, Region, String), String> {
-        let (price, (region, zone, instance_type)) =
-            query_spot_paid_price(&self.client, candidate_regions, &instance_type)
-                .await
-                .map_err(|e| format!("query spot paid price err: {e}"))?;
+    async fn list_saves(&self, server: String, msg: &RecvMsg) {
+        let current = match self
+            .server_status_manager
+            .lock()
+            .await
+            .get_save_name(&server)
+        {
+            Ok(current) => current,
+            Err(e) => {
+                self.bot_instant_tx
+                    .send(msg.reply(e.to_string()))
+                    .await
+                    .unwrap_or_else(Self::err_log);
+                return;
+            }
+        };
+        let content = match self.local_storage.list_saves(&server).await {
+            Ok(saves) if saves.is_empty() => "no saves".to_string(),
+            Ok(saves) => saves
+                .iter()
+                .map(|s| {
+                    let mark = if current.as_ref() == Some(&s.name) {
+                        " *"
+                    } else {
+                        ""
+                    };
+                    format!("{s}{mark}")
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(e) => format!("list saves failed: {e}"),
+        };
         self.bot_instant_tx
-            .send(msg.reply(format!(
-                "Finding lowest price server {} in {} with {}/h + {}/GB",
-                instance_type,
-                region,
-                price.instance_price.unit_price_discount,
-                price.bandwidth_price.unit_price_discount
-            )))
+            .send(msg.reply(content))
             .await
             .unwrap_or_else(Self::err_log);
-        let key_ids = query_key_ids(&self.client)
+    }
+
+    async fn create_server_on(
+        &self,
+        server: &str,
+        candidate: &Candidate,
+        msg: Option<&RecvMsg>,
+        cancel: &Cancel,
+    ) -> Result<(String, String), CreateError> {
+        let client = self
+            .providers
+            .get(Some(&candidate.provider))
+            .map_err(|e| CreateError::Failed(e.to_string()))?;
+        let offer = &candidate.offer;
+        let key_ids = client
+            .query_key_ids()
             .await
-            .map_err(|e| format!("query key err: {e}"))?;
-        let security_group_id = self
-            .client
-            .cvm()
-            .security_group()
-            .describe_security_groups(&region)
+            .map_err(|e| CreateError::Failed(format!("query key err: {e}")))?;
+        let security_group_id = client
+            .query_security_group_ids(&offer.region)
             .await
-            .map_err(|e| format!("find security group err: {e}"))?
-            .into_iter()
-            .filter_map(|sg| {
-                sg.security_group_name
-                    .to_ascii_lowercase()
-                    .contains("palworld")
-                    .then_some(sg.security_group_id)
-            })
-            .collect();
-        let server_id = self
-            .client
-            .cvm()
-            .instances()
-            .run_instance(&region, &zone, &instance_type, key_ids, security_group_id)
+            .map_err(|e| CreateError::Failed(format!("find security group err: {e}")))?;
+        let server_id = client
+            .run_instance(offer, key_ids, security_group_id)
             .await
-            .map_err(|e| format!("init server err: {e}"))?;
-        self.bot_instant_tx
-            .send(msg.reply(format!(
+            .map_err(|e| CreateError::Failed(format!("init server err: {e}")))?;
+        let recorded = self.server_status_manager.lock().await.record_instance(
+            server,
+            &candidate.provider,
+            &offer.region,
+            &server_id,
+        );
+        if let Err(e) = recorded {
+            return Err(
+                match client.terminate_instance(&offer.region, &server_id).await {
+                    Ok(()) => CreateError::Failed(format!("record instance err: {e}")),
+                    Err(te) => CreateError::Leaked(format!(
+                        "record instance err: {e}, {server_id} left running: {te}"
+                    )),
+                },
+            );
+        }
+        self.say(
+            msg,
+            format!(
                 "Success init server, id: {}, install palworld next(will take minutes)",
                 server_id
-            )))
-            .await
-            .unwrap_or_else(Self::err_log);
-        let ip = query_cvm_ip(&self.client, &region, &server_id)
-            .await
-            .map_err(|e| format!("get cvm ip failed :{e}"))?;
-        Ok((ip, region, server_id))
+            ),
+        )
+        .await;
+        let ip = Self::until_cancelled(cancel, async {
+            Ok(client.query_instance_ip(&offer.region, &server_id).await?)
+        })
+        .await;
+        match ip {
+            Ok(ip) => Ok((ip, server_id)),
+            Err(e) => {
+                // terminate it here, the next candidate would be recorded in its place.
+                // if that fails it stays recorded for the start's clean up
+                match client.terminate_instance(&offer.region, &server_id).await {
+                    Ok(()) => {
+                        self.server_status_manager
+                            .lock()
+                            .await
+                            .forget_instance(server)
+                            .unwrap_or_else(Self::err_log);
+                        Err(CreateError::Failed(format!("get cvm ip failed :{e}")))
+                    }
+                    Err(te) => Err(CreateError::Leaked(format!(
+                        "get cvm ip failed :{e}, terminate {server_id} failed: {te}"
+                    ))),
+                }
+            }
+        }
     }
 
-    async fn start_server(&self, server: &str, msg: &RecvMsg) -> Result<(), PSMError> {
-        self.server_status_manager
-            .lock()
-            .await
-            .check_server_status(server, &Status::Stopped)?;
-        let (ip, region, server_id) = async {
+    /// walk down price ranked candidates until one is created and recorded, return (ip, region, instance_id)
+    async fn query_and_create_server(
+        &self,
+        server: &str,
+        instance_type: ServiceInstanceType,
+        placement: &PlacementConfig,
+        msg: Option<&RecvMsg>,
+        cancel: &Cancel,
+    ) -> Result<(String, String, String), String> {
+        // (provider, zone, instance_type) failed within this start
+        let mut failed = HashSet::new();
+        let mut tried = vec![];
+        for _ in 0..MAX_QUERY_ROUNDS {
+            let candidates = self
+                .providers
+                .query_candidates(&instance_type, placement)
+                .await
+                .map_err(|e| format!("query spot paid price err: {e}"))?;
+            let mut has_new = false;
+            for candidate in candidates {
+                let key = (
+                    candidate.provider.clone(),
+                    candidate.offer.zone.clone(),
+                    candidate.offer.instance_type.clone(),
+                );
+                if failed.contains(&key)
+                    || placement
+                        .max_price
+                        .is_some_and(|max_price| candidate.price > max_price)
+                {
+                    continue;
+                }
+                if *cancel.borrow() {
+                    return Err("cancelled".into());
+                }
+                has_new = true;
+                self.say(msg, format!("Finding lowest price server {candidate}"))
+                    .await;
+                match self.create_server_on(server, &candidate, msg, cancel).await {
+                    Ok((ip, server_id)) => return Ok((ip, candidate.offer.region, server_id)),
+                    Err(_) if *cancel.borrow() => return Err("cancelled".into()),
+                    Err(CreateError::Leaked(e)) => {
+                        tried.push(format!("{candidate}: {e}"));
+                        return Err(format!("tried:\n{}", tried.join("\n")));
+                    }
+                    Err(CreateError::Failed(e)) => {
+                        self.say(msg, format!("Failed on {}: {e}", candidate.provider))
+                            .await;
+                        tried.push(format!("{candidate}: {e}"));
+                        failed.insert(key);
+                    }
+                }
+            }
+            if !has_new {
+                break;
+            }
+        }
+        if tried.is_empty() {
+            return Err(format!(
+                "no candidate under max price {:?}",
+                placement.max_price
+            ));
+        }
+        Err(format!("tried:\n{}", tried.join("\n")))
+    }
+
+    /// start a stopped server, clean up the instance and status if anything after the
+    /// Stopped -> Creating transition fails
+    async fn start_or_clean_up(
+        &self,
+        server: &str,
+        region: Option<String>,
+        from: Option<String>,
+        msg: Option<&RecvMsg>,
+    ) -> Result<(), PSMError> {
+        // holding `starting` across the transition, so only the start that made it
+        // registers its cancel. a server in any other state is left as it is
+        let cancel = {
+            let mut starting = self.starting.lock().await;
+            if starting.contains_key(server) {
+                return Err(anyhow::anyhow!("{server} is already starting").into());
+            }
             self.server_status_manager
                 .lock()
                 .await
                 .create_server(server)?;
-            let candidate_regions = vec![Region::Guangzhou, Region::Nanjing, Region::Shanghai];
+            let (cancel_tx, cancel) = watch::channel(false);
+            starting.insert(server.into(), cancel_tx);
+            cancel
+        };
+        let res = self.start_server(server, region, from, msg, &cancel).await;
+        self.starting.lock().await.remove(server);
+        if res.is_err() {
+            let cleanup = self
+                .server_status_manager
+                .lock()
+                .await
+                .failed_create_server(server);
+            match cleanup {
+                Ok((provider, Some(instance_id), Some(region))) => {
+                    let terminated = match self.providers.get(provider.as_deref()) {
+                        Ok(client) => client.terminate_instance(&region, &instance_id).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = terminated {
+                        Self::err_log(&e);
+                        self.say(
+                            msg,
+                            format!("Failed to terminate {instance_id} in {region}, terminate it by hand: {e}"),
+                        )
+                        .await;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => Self::err_log(e),
+            }
+        }
+        res
+    }
+
+    async fn start_server(
+        &self,
+        server: &str,
+        region: Option<String>,
+        from: Option<String>,
+        msg: Option<&RecvMsg>,
+        cancel: &Cancel,
+    ) -> Result<(), PSMError> {
+        let (ip, region, server_id) = async {
             let instance_type: ServiceInstanceType = self
                 .server_status_manager
                 .lock()
                 .await
                 .get_instance_type(server)?
                 .try_into()?;
-            let mut try_cnt = 0;
-            let (ip, region, server_id) = {
-                loop {
-                    match self
-                        .query_and_create_server(&candidate_regions, instance_type.clone(), msg)
-                        .await
-                    {
-                        Ok(r) => break r,
-                        Err(e) => {
-                            try_cnt += 1;
-                            if try_cnt == 5 {
-                                return Err(PSMError::CSPClientError(format!(
-                                    "err to create server: {e}"
-                                )));
-                            }
-                        }
-                    }
-                }
-            };
-            Ok((ip, region, server_id))
+            let mut placement = self
+                .server_status_manager
+                .lock()
+                .await
+                .get_placement(server, &self.config.placement)?;
+            if let Some(region) = region {
+                placement.regions = vec![region];
+            }
+            let (ip, region, server_id) = self
+                .query_and_create_server(server, instance_type, &placement, msg, cancel)
+                .await
+                .map_err(|e| PSMError::CSPClientError(format!("err to create server: {e}")))?;
+            Ok::<_, PSMError>((ip, region, server_id))
         }
         .await?;
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        Self::until_cancelled(cancel, async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        })
+        .await?;
 
         // upload script
         self.local_storage
-            .upload_scripts(&self.shell_manager.ssh_config, &ip)
+            .upload_scripts(self.shell_manager.as_ref(), &ip)
             .await?;
 
         // add build server script exec
-        self.shell_manager.run(&ip, Script::InstallServer).await?;
+        self.shell_manager
+            .run(
+                &ip,
+                Script::InstallServer,
+                &self.script_progress(msg.cloned(), "install palworld"),
+                cancel,
+            )
+            .await?;
 
-        let save_name = self
-            .server_status_manager
-            .lock()
-            .await
-            .get_save_name(server)?;
+        let save_name = match from {
+            Some(from) => Some(from),
+            None => self
+                .server_status_manager
+                .lock()
+                .await
+                .get_save_name(server)?,
+        };
         if let Some(save_name) = save_name {
             // sftp bk files
-            self.local_storage
-                .upload_saves(&save_name, &self.shell_manager.ssh_config, &ip)
-                .await?;
+            let (replica, checksum) = Self::until_cancelled(cancel, async {
+                Ok(self
+                    .local_storage
+                    .upload_saves(
+                        server,
+                        &save_name,
+                        self.shell_manager.as_ref(),
+                        &ip,
+                        &self.transfer_progress(msg.cloned(), format!("upload {save_name}")),
+                    )
+                    .await?)
+            })
+            .await?;
             // restore bk saves
-            self.shell_manager.run(&ip, Script::RestoreSave).await?;
-            self.bot_instant_tx
-                .send(msg.reply(format!("Success load save, {}", save_name)))
+            self.shell_manager
+                .run(
+                    &ip,
+                    Script::RestoreSave(server.into(), save_name.clone(), checksum),
+                    &|_| (),
+                    cancel,
+                )
                 .await?;
+            self.say(
+                msg,
+                format!("Success load save, {} from {}", save_name, replica),
+            )
+            .await;
         }
 
         // server start
-        self.shell_manager.run(&ip, Script::StartServer).await?;
-        let ip_port = format!("{}:8211", ip);
-        self.bot_instant_tx
-            .send(msg.reply(format!("Success create server, ip-port: {ip_port}")))
+        self.shell_manager
+            .run(&ip, Script::StartServer, &|_| (), cancel)
             .await?;
+        let ip_port = format!("{}:8211", ip);
+        self.say(msg, format!("Success create server, ip-port: {ip_port}"))
+            .await;
         self.server_status_manager
             .lock()
             .await
-            .finish_creating_server(server, &ip_port, &region.to_string(), &server_id)?;
+            .finish_creating_server(server, &ip_port, &region, &server_id)?;
 
         Ok(())
     }
@@ -255,14 +645,116 @@ impl PalTaskHandler {
             .await
             .get_server_ip(server)?
             .ok_or(anyhow::anyhow!("failed to get server ip infomation"))?;
-        let save_name = self.shell_manager.run(&ip, Script::BackupSave).await?;
-        self.local_storage
-            .download_saves(&save_name, &self.shell_manager.ssh_config, &ip)
+        let outcome = self
+            .shell_manager
+            .run(
+                &ip,
+                Script::BackupSave(server.into()),
+                &|_| (),
+                &no_cancel(),
+            )
             .await?;
+        let Some(ScriptResult {
+            save: Some(save_name),
+            checksum: Some(checksum),
+        }) = outcome.result
+        else {
+            return Err(anyhow::anyhow!(
+                "backup {} gave no save: {}",
+                outcome.run_id,
+                outcome.last_line
+            )
+            .into());
+        };
+        let reply_to = self.reply_to.lock().await.get(server).cloned();
+        let reports = self
+            .local_storage
+            .download_saves(
+                server,
+                &save_name,
+                &checksum,
+                self.shell_manager.as_ref(),
+                &ip,
+                &self.transfer_progress(reply_to, format!("download {save_name}")),
+            )
+            .await?;
+        if reports.len() > 1 {
+            let reports = reports.iter().map(|r| r.to_string()).join("\n");
+            self.notify(server, format!("Save {save_name} replicas:\n{reports}"))
+                .await;
+        }
+        let mut protected: Vec<_> = self
+            .server_status_manager
+            .lock()
+            .await
+            .get_save_name(server)?
+            .into_iter()
+            .collect();
+        protected.push(save_name.clone());
+        // the new save is already safe, pruning failure should not fail the backup
+        self.local_storage
+            .prune_saves(server, &protected)
+            .await
+            .map(|pruned| debug!("pruned saves: {pruned:?}"))
+            .unwrap_or_else(Self::err_log);
         Ok(save_name)
     }
 
-    async fn stop_server(&self, server: &str, msg: &RecvMsg) -> Result<(), PSMError> {
+    async fn backup_due(&self, server: &str, last: Instant) -> bool {
+        let Ok(backup) = self
+            .server_status_manager
+            .lock()
+            .await
+            .get_backup_config(server, &self.config.backup)
+        else {
+            return false;
+        };
+        let Some(interval) = backup.interval else {
+            return false;
+        };
+        let quiet = backup
+            .quiet_hours
+            .is_some_and(|q| q.contains(chrono::Local::now().hour()));
+        !quiet && last.elapsed() >= Duration::from_secs(interval * 60)
+    }
+
+    async fn periodic_backup(&self, server: &str) {
+        let lock = self.save_lock(server).await;
+        let Ok(_guard) = lock.try_lock() else {
+            debug!("skip periodic backup of busy {server}");
+            return;
+        };
+        if self
+            .server_status_manager
+            .lock()
+            .await
+            .check_server_status(server, &Status::Running)
+            .is_err()
+        {
+            return;
+        }
+        let result = match self.backup_save(server).await {
+            Ok(save_name) => self
+                .server_status_manager
+                .lock()
+                .await
+                .update_save_name(server, &save_name)
+                .map(|_| save_name)
+                .map_err(PSMError::from),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(save_name) => info!("periodic backup of {server}: {save_name}"),
+            Err(e) => {
+                self.notify(server, format!("Periodic backup of {server} failed: {e}"))
+                    .await
+            }
+        }
+    }
+
+    async fn stop_server(&self, server: &str, msg: Option<&RecvMsg>) -> Result<(), PSMError> {
+        let lock = self.save_lock(server).await;
+        let _guard = lock.lock().await;
         self.server_status_manager
             .lock()
             .await
@@ -275,92 +767,240 @@ impl PalTaskHandler {
             .await
             .update_save_name(server, &save_name)?;
 
-        let (region, instance_id) = self
+        let (provider, region, instance_id) = self
             .server_status_manager
             .lock()
             .await
             .stop_server(server)?;
-        let region = Region::from_str(&region).unwrap();
-        self.client
-            .cvm()
-            .instances()
+        let terminated = async {
+            self.providers
+                .get(provider.as_deref())?
+                .terminate_instance(&region, &instance_id)
+                .await
+        }
+        .await;
+        if let Err(e) = terminated {
+            // this stop moved it to Stopping, the instance is still there
+            self.server_status_manager
+                .lock()
+                .await
+                .failed_stop_server(server)
+                .unwrap_or_else(Self::err_log);
+            return Err(e.into());
+        }
+        self.say(
+            msg,
+            format!("Success delete server {server} instance id: {instance_id}",),
+        )
+        .await;
+        self.server_status_manager
+            .lock()
+            .await
+            .finish_stopping_server(server)?;
+
+        Ok(())
+    }
+
+    fn spawn_reclaim_watcher(&self, server: String) {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            handler.watch_reclaim(&server).await;
+        });
+    }
+
+    /// poll until the instance is gone or being reclaimed, then rescue its save
+    async fn watch_reclaim(&self, server: &str) {
+        let Ok(Some((provider, region, instance_id))) = self
+            .server_status_manager
+            .lock()
+            .await
+            .get_running_instance(server)
+        else {
+            return;
+        };
+        let Ok(client) = self.providers.get(provider.as_deref()) else {
+            return;
+        };
+        debug!("watch reclaim of {server} instance {instance_id}");
+        loop {
+            tokio::time::sleep(Duration::from_secs(RECLAIM_CHECK_INTERVAL)).await;
+            // stop watching once the server stopped or runs another instance
+            let running = self
+                .server_status_manager
+                .lock()
+                .await
+                .get_running_instance(server);
+            if !matches!(running, Ok(Some((_, _, ref id))) if *id == instance_id) {
+                break;
+            }
+            let reclaiming = match client.is_reclaiming(&region, &instance_id).await {
+                Ok(true) => true,
+                Ok(false) => self.has_termination_notice(server, client.as_ref()).await,
+                Err(e) => {
+                    debug!("query reclaim status of {instance_id} failed: {e}");
+                    false
+                }
+            };
+            if reclaiming {
+                if let Err(e) = self.rescue_reclaimed_server(server).await {
+                    self.notify(server, format!("rescue server {server} failed: {e}"))
+                        .await;
+                }
+                break;
+            }
+        }
+    }
+
+    async fn has_termination_notice(&self, server: &str, client: &dyn CloudProvider) -> bool {
+        let Some(url) = client.termination_notice_url() else {
+            return false;
+        };
+        let Ok(Some(ip)) = self
+            .server_status_manager
+            .lock()
+            .await
+            .get_server_ip(server)
+        else {
+            return false;
+        };
+        let token = client.metadata_token_url().map_or(String::new(), |token_url| {
+            format!(
+                "-H \"X-aws-ec2-metadata-token: $(curl -s -m 5 -X PUT -H 'X-aws-ec2-metadata-token-ttl-seconds: 60' {token_url})\" "
+            )
+        });
+        // exec has no session timeout, curl bounds itself
+        self.shell_manager
+            .exec(
+                &ip,
+                &format!("curl -s -m 5 {token}-o /dev/null -w '%{{http_code}}' {url}"),
+            )
+            .await
+            .is_ok_and(|code| code.trim() == "200")
+    }
+
+    /// save what we can from a reclaimed instance and mark the server stopped
+    async fn rescue_reclaimed_server(&self, server: &str) -> Result<(), PSMError> {
+        let lock = self.save_lock(server).await;
+        let _guard = lock.lock().await;
+        self.server_status_manager
+            .lock()
+            .await
+            .check_server_status(server, &Status::Running)?;
+        self.notify(
+            server,
+            format!("Spot instance of {server} is being reclaimed, backup save now"),
+        )
+        .await;
+        match self.backup_save(server).await {
+            Ok(save_name) => {
+                self.server_status_manager
+                    .lock()
+                    .await
+                    .update_save_name(server, &save_name)?;
+                self.notify(server, format!("Rescued save {save_name}"))
+                    .await;
+            }
+            Err(e) => {
+                self.notify(server, format!("Rescue save failed: {e}"))
+                    .await;
+            }
+        }
+        let (provider, region, instance_id) = self
+            .server_status_manager
+            .lock()
+            .await
+            .stop_server(server)?;
+        if let Err(e) = self
+            .providers
+            .get(provider.as_deref())?
             .terminate_instance(&region, &instance_id)
-            .await?;
-        self.bot_instant_tx
-            .send(msg.reply(format!(
-                "Success delete server {server} instance id: {instance_id}",
-            )))
             .await
-            .unwrap_or_else(Self::err_log);
+        {
+            debug!("terminate reclaimed instance {instance_id}: {e}");
+        }
         self.server_status_manager
             .lock()
             .await
             .finish_stopping_server(server)?;
-
+        self.notify(server, format!("Server {server} stopped after reclaim"))
+            .await;
         Ok(())
     }
 
-    async fn save_server(&self, server: &str, msg: &RecvMsg) -> Result<(), PSMError> {
+    async fn save_server(&self, server: &str, msg: Option<&RecvMsg>) -> Result<(), PSMError> {
+        let lock = self.save_lock(server).await;
+        let _guard = lock.lock().await;
         self.server_status_manager
             .lock()
             .await
             .check_server_status(server, &Status::Running)?;
         let save_name = self.backup_save(server).await?;
-        self.bot_instant_tx
-            .send(msg.reply(format!("back save success {save_name}")))
-            .await
-            .unwrap_or_else(Self::err_log);
+        self.say(msg, format!("back save success {save_name}"))
+            .await;
         Ok(())
     }
 
-    pub async fn handle_server_cmd(
-        &self,
-        status: Option<String>,
-        start: Option<String>,
-        stop: Option<String>,
-        save: Option<String>,
-        msg: &RecvMsg,
-    ) -> Option<SendMsg> {
+    pub async fn handle_server_cmd(&self, args: ServerArgs, msg: &RecvMsg) -> Option<SendMsg> {
+        let ServerArgs {
+            status,
+            start,
+            region,
+            from,
+            stop,
+            save,
+            saves,
+            cancel,
+        } = args;
+        if let Some(region) = &region {
+            if !self.providers.is_known_region(region) {
+                return Some(msg.reply(format!("unknown region {region}")));
+            }
+        }
+        if let Some((server, from)) = start.as_ref().zip(from.as_ref()) {
+            match self.local_storage.has_save(server, from).await {
+                Ok(true) => {}
+                Ok(false) => return Some(msg.reply(format!("archive {from} not found"))),
+                Err(e) => return Some(msg.reply(e.to_string())),
+            }
+        }
+        for server in [&start, &stop, &save].into_iter().flatten() {
+            self.reply_to
+                .lock()
+                .await
+                .insert(server.clone(), msg.clone());
+        }
         if let Some(server) = status {
             self.list_server(server, msg).await;
         }
+        if let Some(server) = saves {
+            self.list_saves(server, msg).await;
+        }
+        if let Some(server) = cancel {
+            self.cancel_start(server, msg).await;
+        }
         if let Some(server) = start {
-            if let Err(e) = self.start_server(&server, msg).await {
+            if let Err(e) = self
+                .start_or_clean_up(&server, region, from, Some(msg))
+                .await
+            {
                 self.bot_instant_tx
                     .send(msg.reply(e.to_string()))
                     .await
                     .unwrap_or_else(Self::err_log);
-                let res = self
-                    .server_status_manager
-                    .lock()
-                    .await
-                    .failed_create_server(&server)
-                    .unwrap();
-                if let (Some(instance_id), Some(region)) = res {
-                    self.client
-                        .cvm()
-                        .instances()
-                        .terminate_instance(&Region::from_str(&region).unwrap(), &instance_id)
-                        .await
-                        .unwrap();
-                }
+            } else {
+                self.spawn_reclaim_watcher(server);
             }
         }
         if let Some(server) = stop {
-            if let Err(e) = self.stop_server(&server, msg).await {
+            if let Err(e) = self.stop_server(&server, Some(msg)).await {
                 self.bot_instant_tx
                     .send(msg.reply(e.to_string()))
                     .await
                     .unwrap_or_else(Self::err_log);
-                self.server_status_manager
-                    .lock()
-                    .await
-                    .failed_stop_server(&server)
-                    .unwrap();
             }
         }
         if let Some(server) = save {
-            if let Err(e) = self.save_server(&server, msg).await {
+            if let Err(e) = self.save_server(&server, Some(msg)).await {
                 self.bot_instant_tx
                     .send(msg.reply(e.to_string()))
                     .await
@@ -375,30 +1015,18 @@ impl PalTaskHandler {
             return Some(msg.reply("ip format error".into()));
         }
         let nps_access = &self.config.nps;
-        let region = Region::from_str(&nps_access.region).unwrap();
-        let instance_id = &nps_access.instance_id;
-        let target_protocol = &nps_access.protocol;
-        let target_port = &nps_access.port;
-        let mut firewallrules = self
-            .client
-            .lighthouse()
-            .firewall()
-            .describe_firewall_rules(&region, instance_id)
-            .await
-            .unwrap_or_default()
-            .response
-            .firewall_rule_set;
-        firewallrules
-            .iter_mut()
-            .filter(|r| r.port == *target_port && r.protocol == *target_protocol)
-            .for_each(|r| {
-                r.cidr_block = ip.clone();
-            });
-        let content = match self
-            .client
-            .lighthouse()
-            .firewall()
-            .modify_firewall_rules(&region, instance_id, firewallrules)
+        let client = match self.providers.get(nps_access.provider.as_deref()) {
+            Ok(client) => client,
+            Err(e) => return Some(msg.reply(e.to_string())),
+        };
+        let content = match client
+            .modify_firewall_rules(
+                &nps_access.region,
+                &nps_access.instance_id,
+                &nps_access.protocol,
+                &nps_access.port,
+                &ip,
+            )
             .await
         {
             Ok(_) => "Success modify firewall rules".to_string(),
@@ -408,6 +1036,24 @@ impl PalTaskHandler {
     }
 }
 
+/// post `content` to the group through the OneBot `send_group_msg` http api
+async fn send_group_msg(target: &NotifyConfig, content: &str) -> anyhow::Result<()> {
+    let mut request = reqwest::Client::new()
+        .post(format!(
+            "{}/send_group_msg",
+            target.http_api.trim_end_matches('/')
+        ))
+        .json(&serde_json::json!({ "group_id": target.group_id, "message": content }));
+    if let Some(token) = &target.access_token {
+        request = request.bearer_auth(token);
+    }
+    let resp: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+    match resp["retcode"].as_i64() {
+        Some(0) => Ok(()),
+        _ => Err(anyhow::anyhow!("send group msg failed: {resp}")),
+    }
+}
+
 const DEFAULT_REPLY: &str = "使用 `#--help` 来查询命令";
 
 #[async_trait]
@@ -421,15 +1067,7 @@ impl Handler for PalTaskHandler {
         info!("psm recv cmd: {cmd:?}");
         if let Some(cmd) = cmd.sub {
             let res = match cmd {
-                Commands::Server {
-                    status,
-                    start,
-                    stop,
-                    save,
-                } => {
-                    self.handle_server_cmd(status, start, stop, save, &msg)
-                        .await
-                }
+                Commands::Server(args) => self.handle_server_cmd(args, &msg).await,
                 Commands::Config { r#type: _type } => None,
                 Commands::Nps { ip } => self.handle_nps_cmd(ip, &msg).await,
             };
@@ -440,7 +1078,7 @@ impl Handler for PalTaskHandler {
     async fn check_cmd_auth(&self, cmd: &Self::Cmd, ori_msg: &RecvMsg, root_id: u64) -> bool {
         let white_list = &self.config.whitelist;
         let allow_act = cmd.sub.as_ref().is_some_and(|c| match c {
-            Commands::Server { .. } => white_list.server.contains(&ori_msg.from_id),
+            Commands::Server(_) => white_list.server.contains(&ori_msg.from_id),
             Commands::Config { .. } => ori_msg.from_id == root_id,
             Commands::Nps { .. } => white_list.nps.contains(&ori_msg.from_id),
         });
@@ -448,3 +1086,601 @@ impl Handler for PalTaskHandler {
         allow_act
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::atomic::{AtomicBool, AtomicUsize},
+    };
+
+    use opendal::{services::Fs, Operator};
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{csp::InstanceOffer, server_status::Server, shell_manager::ScriptOutcome};
+
+    const SERVER: &str = "pal";
+
+    /// an instance whose files are a temp dir and whose scripts only touch saves there
+    struct MockShell {
+        dir: TempDir,
+        /// scripts run, in order
+        runs: std::sync::Mutex<Vec<String>>,
+        backups: AtomicUsize,
+        fail_backup: AtomicBool,
+    }
+
+    impl MockShell {
+        fn new() -> Self {
+            Self {
+                dir: TempDir::new().unwrap(),
+                runs: std::sync::Mutex::new(vec![]),
+                backups: AtomicUsize::new(0),
+                fail_backup: AtomicBool::new(false),
+            }
+        }
+
+        fn save(&self, save_name: &str) -> std::path::PathBuf {
+            self.dir.path().join("saves").join(SERVER).join(save_name)
+        }
+
+        fn runs(&self) -> Vec<String> {
+            self.runs.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl RemoteShell for MockShell {
+        async fn exec(&self, _ip: &str, command: &str) -> anyhow::Result<String> {
+            anyhow::bail!("mock shell can't exec `{command}`")
+        }
+
+        async fn run(
+            &self,
+            _ip: &str,
+            script: Script,
+            _progress: &(dyn Fn(ScriptProgress) + Send + Sync),
+            _cancel: &Cancel,
+        ) -> anyhow::Result<ScriptOutcome> {
+            self.runs.lock().unwrap().push(format!("{script:?}"));
+            let result = match script {
+                Script::BackupSave(_) => {
+                    anyhow::ensure!(
+                        !self.fail_backup.load(Ordering::SeqCst),
+                        "backup_save.sh exited with 1"
+                    );
+                    // names are by the second, number them instead
+                    let n = self.backups.fetch_add(1, Ordering::SeqCst);
+                    let save_name = format!("Saved.202401011200{n:02}.tar.gz");
+                    let content = format!("save {n}");
+                    std::fs::create_dir_all(self.save(&save_name).parent().unwrap())?;
+                    std::fs::write(self.save(&save_name), &content)?;
+                    Some(ScriptResult {
+                        save: Some(save_name),
+                        checksum: Some(hex::encode(Sha256::digest(content))),
+                    })
+                }
+                Script::RestoreSave(_, save_name, checksum) => {
+                    let content = std::fs::read(self.save(&save_name))?;
+                    anyhow::ensure!(
+                        hex::encode(Sha256::digest(content)) == checksum,
+                        "checksum mismatch of {save_name}"
+                    );
+                    None
+                }
+                Script::InstallServer | Script::StartServer => None,
+            };
+            Ok(ScriptOutcome {
+                run_id: "mock".into(),
+                last_line: String::new(),
+                result,
+            })
+        }
+
+        fn files(&self, _ip: &str, _root: &str) -> anyhow::Result<Operator> {
+            let mut fs = Fs::default();
+            fs.root(self.dir.path().to_str().unwrap());
+            Ok(Operator::new(fs)?.finish())
+        }
+    }
+
+    /// a handler over mock providers, with its status file and storage in a temp dir
+    struct Fixture {
+        dir: TempDir,
+        handler: PalTaskHandler,
+    }
+
+    impl Fixture {
+        /// `csp` is a yaml list of providers, scripts are only there with `ssh` (user, prikey)
+        fn new(csp: &str, status: &str, ssh: Option<(&str, &str)>) -> Self {
+            let dir = TempDir::new().unwrap();
+            std::fs::create_dir_all(dir.path().join("scripts")).unwrap();
+            let (user, prikey) = ssh.unwrap_or(("psm", "/nonexistent/key"));
+            if ssh.is_some() {
+                copy_scripts(&dir);
+            }
+            let config: PsmConfig = serde_yaml::from_str(&format!(
+                r#"
+csp:
+{csp}
+storage:
+  local:
+    local_dir: {dir}
+    remote_dir: /home/{user}/psm
+ssh:
+  prikey: {prikey}
+  user: {user}
+nps: {{ region: r1, instance_id: nps, protocol: tcp, port: "80" }}
+whitelist: {{ server: [], nps: [] }}
+"#,
+                dir = dir.path().display(),
+            ))
+            .unwrap();
+            let status_path = dir.path().join("server_status.yaml");
+            std::fs::write(&status_path, status).unwrap();
+            let (tx, _) = tokio::sync::mpsc::channel(10);
+            let handler = PalTaskHandler::new(
+                Arc::new(CloudProviders::new(&config.csp.providers())),
+                Arc::new(tx),
+                Arc::new(Mutex::new(ServerManager::new(&status_path))),
+                Arc::new(ShellManager::new(config.ssh.clone())),
+                Arc::new(LocalStorage::new(config.storage.replicas()).unwrap()),
+                Arc::new(config),
+            );
+            Self { dir, handler }
+        }
+
+        /// like `new`, with a `MockShell` as the instance instead of ssh
+        fn with_mock_shell(csp: &str, status: &str) -> (Self, Arc<MockShell>) {
+            let mut fixture = Self::new(csp, status, None);
+            copy_scripts(&fixture.dir);
+            let shell = Arc::new(MockShell::new());
+            fixture.handler.shell_manager = shell.clone();
+            (fixture, shell)
+        }
+
+        /// the server as written to the status file
+        fn server(&self) -> Server {
+            let data = std::fs::read_to_string(self.dir.path().join("server_status.yaml")).unwrap();
+            let servers: Vec<Server> = serde_yaml::from_str(&data).unwrap();
+            servers.into_iter().find(|s| s.name == SERVER).unwrap()
+        }
+
+        /// a mock instance in r1-a the server runs on, as if started earlier
+        async fn run_on_mock(&self) -> String {
+            let offer = InstanceOffer {
+                region: "r1".into(),
+                zone: "r1-a".into(),
+                instance_type: "MOCK.T2C2G".into(),
+                instance_price: 1.0,
+                bandwidth_price: 0.0,
+            };
+            let instance_id = self
+                .handler
+                .providers
+                .get(Some("mock"))
+                .unwrap()
+                .run_instance(&offer, vec![], vec![])
+                .await
+                .unwrap();
+            let mut servers = self.handler.server_status_manager.lock().await;
+            servers.create_server(SERVER).unwrap();
+            servers
+                .record_instance(SERVER, "mock", "r1", &instance_id)
+                .unwrap();
+            servers
+                .finish_creating_server(SERVER, "127.0.0.1:8211", "r1", &instance_id)
+                .unwrap();
+            instance_id
+        }
+
+        async fn terminate(&self, provider: &str, instance_id: &str) -> anyhow::Result<()> {
+            self.handler
+                .providers
+                .get(Some(provider))?
+                .terminate_instance("r1", instance_id)
+                .await
+        }
+    }
+
+    /// the scripts `upload_scripts` sends, into `local_dir/scripts`
+    fn copy_scripts(dir: &TempDir) {
+        let scripts = Path::new(env!("CARGO_MANIFEST_DIR")).join("scripts");
+        for entry in std::fs::read_dir(scripts).unwrap() {
+            let path = entry.unwrap().path();
+            let target = dir.path().join("scripts").join(path.file_name().unwrap());
+            std::fs::copy(&path, target).unwrap();
+        }
+    }
+
+    fn stopped_server() -> String {
+        format!("- name: {SERVER}\n  status: Stopped\n  instance_type: 2c2g\n")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_falls_back_to_next_provider_and_cleans_up() {
+        // the cheaper provider is always sold out, the other one boots slowly
+        let csp = r#"
+  - name: sold_out
+    mock:
+      regions: { r1: [r1-a, r1-b] }
+      price: 0.5
+      failure_rate: 1.0
+  - name: spare
+    mock:
+      regions: { r1: [r1-c] }
+      price: 2.0
+      boot_delay: 30
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+        let started = tokio::time::Instant::now();
+
+        // the instance is created on the spare, then the script upload fails without ssh
+        let err = fixture
+            .handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(!matches!(err, PSMError::CSPClientError(_)), "{err}");
+        assert!(started.elapsed() >= Duration::from_secs(30));
+
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Stopped);
+        assert_eq!(server.provider, None);
+        assert_eq!(server.instance_id, None);
+        // the spare instance was terminated by the cleanup
+        assert!(fixture.terminate("spare", "mock-1").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_stops_at_an_instance_left_running() {
+        // the cheaper provider's instance never boots and can't be terminated
+        let csp = r#"
+  - name: broken
+    mock:
+      regions: { r1: [r1-a] }
+      price: 0.5
+      boot_failure: true
+      terminate_failure: true
+  - name: spare
+    mock:
+      regions: { r1: [r1-c] }
+      price: 2.0
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+
+        let err = fixture
+            .handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PSMError::CSPClientError(_)), "{err}");
+        let err = err.to_string();
+        assert!(err.contains("terminate mock-1 failed"), "{err}");
+        // the spare is never tried, so it can't take over the record of the running instance
+        assert!(!err.contains("spare"), "{err}");
+
+        let broken = fixture.handler.providers.get(Some("broken")).unwrap();
+        assert!(!broken.is_reclaiming("r1", "mock-1").await.unwrap());
+        assert_eq!(fixture.server().status, Status::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_reports_every_sold_out_candidate() {
+        let csp = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a, r1-b] }
+      failure_rate: 1.0
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+
+        let err = fixture
+            .handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap_err();
+        let PSMError::CSPClientError(err) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert!(err.contains("sold out in r1-a"), "{err}");
+        assert!(err.contains("sold out in r1-b"), "{err}");
+        assert_eq!(fixture.server().status, Status::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_leaves_busy_server_alone() {
+        let csp = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a] }
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+        let instance_id = fixture.run_on_mock().await;
+
+        let err = fixture
+            .handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PSMError::ServerManagerError(_)), "{err}");
+
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Running);
+        assert_eq!(server.instance_id.as_deref(), Some(instance_id.as_str()));
+        // still alive until terminated here
+        fixture.terminate("mock", &instance_id).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reclaimed_server_is_stopped() {
+        let csp = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a] }
+      reclaim_after: 120
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+        let instance_id = fixture.run_on_mock().await;
+
+        // the rescue backup fails without ssh, the server is stopped anyway
+        tokio::time::timeout(
+            Duration::from_secs(300),
+            fixture.handler.watch_reclaim(SERVER),
+        )
+        .await
+        .expect("watcher should exit after the rescue");
+
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Stopped);
+        assert_eq!(server.instance_id, None);
+        assert!(fixture.terminate("mock", &instance_id).await.is_err());
+
+        // a stop asked for meanwhile has nothing to stop, and must not bring it back to Running
+        assert!(fixture.handler.stop_server(SERVER, None).await.is_err());
+        assert_eq!(fixture.server().status, Status::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_interrupts_the_boot_wait() {
+        let csp = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a] }
+      boot_delay: 30
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+        let started = tokio::time::Instant::now();
+
+        // cancel 20s into the 30s boot, the instance is already recorded by then
+        let canceller = async {
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            assert_eq!(fixture.server().instance_id.as_deref(), Some("mock-1"));
+            fixture.handler.starting.lock().await[SERVER].send_replace(true);
+        };
+        let (res, _) = tokio::join!(
+            fixture.handler.start_or_clean_up(SERVER, None, None, None),
+            canceller
+        );
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(30));
+
+        assert_eq!(fixture.server().status, Status::Stopped);
+        assert!(fixture.terminate("mock", "mock-1").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn second_start_keeps_first_cancellable() {
+        let csp = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a] }
+      boot_delay: 30
+"#;
+        let fixture = Fixture::new(csp, &stopped_server(), None);
+
+        let handler = fixture.handler.clone();
+        let second = async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            let err = handler
+                .start_or_clean_up(SERVER, None, None, None)
+                .await
+                .unwrap_err();
+            // the first start can still be cancelled after the refused one
+            handler.starting.lock().await[SERVER].send_replace(true);
+            err
+        };
+        let (first, second) = tokio::join!(
+            fixture.handler.start_or_clean_up(SERVER, None, None, None),
+            second
+        );
+        assert!(second.to_string().contains("already starting"), "{second}");
+        let first = first.unwrap_err();
+        assert!(first.to_string().contains("cancelled"), "{first}");
+        assert!(fixture.handler.starting.lock().await.is_empty());
+        assert_eq!(fixture.server().status, Status::Stopped);
+    }
+
+    /// providers priced in CNY, USD and a free local one demoted by its penalty
+    const MIXED_CURRENCY_CSP: &str = r#"
+  - name: cny
+    currency_rate: 0.14
+    mock:
+      regions: { r1: [r1-a] }
+      price: 10.0
+  - name: usd
+    mock:
+      regions: { r1: [r1-b] }
+      price: 2.0
+  - name: local
+    penalty: 5.0
+    mock:
+      regions: { r1: [r1-c] }
+      price: 0.0
+"#;
+
+    #[tokio::test]
+    async fn candidates_are_ranked_in_common_currency() {
+        let fixture = Fixture::new(MIXED_CURRENCY_CSP, &stopped_server(), None);
+
+        let candidates = fixture
+            .handler
+            .providers
+            .query_candidates(
+                &ServiceInstanceType::try_from("2c2g".to_string()).unwrap(),
+                &PlacementConfig::default(),
+            )
+            .await
+            .unwrap();
+        let ranked: Vec<_> = candidates.iter().map(|c| c.provider.as_str()).collect();
+        assert_eq!(ranked, ["cny", "usd", "local"]);
+        assert!((candidates[0].price - 1.4).abs() < 1e-9);
+        assert_eq!(candidates[2].price, 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_price_applies_to_converted_price() {
+        // cny is 1.4 once converted, usd 2.0, so only the sold out local one is tried
+        let csp = MIXED_CURRENCY_CSP.replace("price: 0.0", "price: 0.0\n      failure_rate: 1.0");
+        let status = format!("{}  max_price: 1.0\n", stopped_server());
+        let fixture = Fixture::new(&csp, &status, None);
+
+        let err = fixture
+            .handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap_err();
+        let PSMError::CSPClientError(err) = err else {
+            panic!("unexpected error {err}");
+        };
+        assert!(err.contains("on local"), "{err}");
+        assert!(!err.contains("on cny") && !err.contains("on usd"), "{err}");
+    }
+
+    const MOCK_CSP: &str = r#"
+  - name: mock
+    mock:
+      regions: { r1: [r1-a] }
+"#;
+
+    #[tokio::test(start_paused = true)]
+    async fn start_save_stop_and_restore() {
+        let (fixture, shell) = Fixture::with_mock_shell(MOCK_CSP, &stopped_server());
+        let handler = &fixture.handler;
+
+        handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(fixture.server().status, Status::Running);
+        assert!(shell.dir.path().join("scripts/start_server.sh").is_file());
+
+        handler.save_server(SERVER, None).await.unwrap();
+        handler.stop_server(SERVER, None).await.unwrap();
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Stopped);
+        assert_eq!(server.instance_id, None);
+        assert!(fixture.terminate("mock", "mock-1").await.is_err());
+        // the stop backs up again and keeps that one
+        let saved = handler.local_storage.list_saves(SERVER).await.unwrap();
+        let saved: Vec<_> = saved.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            saved,
+            ["Saved.20240101120001.tar.gz", "Saved.20240101120000.tar.gz"]
+        );
+        assert_eq!(server.save.as_deref(), Some(saved[0]));
+
+        // start over on a fresh instance from the older save, it is uploaded and verified
+        std::fs::remove_dir_all(shell.dir.path().join("saves")).unwrap();
+        handler
+            .start_or_clean_up(SERVER, None, Some(saved[1].into()), None)
+            .await
+            .unwrap();
+        assert!(shell.save(saved[1]).is_file());
+        let restore = format!(
+            "RestoreSave(\"pal\", \"{}\", \"{}\")",
+            saved[1],
+            hex::encode(Sha256::digest("save 0"))
+        );
+        assert_eq!(
+            shell.runs(),
+            [
+                "InstallServer",
+                "StartServer",
+                "BackupSave(\"pal\")",
+                "BackupSave(\"pal\")",
+                "InstallServer",
+                restore.as_str(),
+                "StartServer",
+            ]
+        );
+        fixture.terminate("mock", "mock-2").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_backup_keeps_server_running() {
+        let (fixture, shell) = Fixture::with_mock_shell(MOCK_CSP, &stopped_server());
+        let instance_id = fixture.run_on_mock().await;
+        shell.fail_backup.store(true, Ordering::SeqCst);
+
+        assert!(fixture.handler.save_server(SERVER, None).await.is_err());
+        let err = fixture.handler.stop_server(SERVER, None).await.unwrap_err();
+        assert!(err.to_string().contains("backup_save.sh"), "{err}");
+
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Running);
+        assert_eq!(server.save, None);
+        fixture.terminate("mock", &instance_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_terminate_rolls_stop_back() {
+        let (fixture, _shell) = Fixture::with_mock_shell(MOCK_CSP, &stopped_server());
+        let instance_id = fixture.run_on_mock().await;
+        // the provider no longer knows the instance, so terminating it fails
+        fixture.terminate("mock", &instance_id).await.unwrap();
+
+        assert!(fixture.handler.stop_server(SERVER, None).await.is_err());
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Running);
+        assert_eq!(server.instance_id.as_deref(), Some(instance_id.as_str()));
+        // the save taken before terminating is kept
+        assert_eq!(server.save.as_deref(), Some("Saved.20240101120000.tar.gz"));
+    }
+
+    /// start, save and stop on a real host, it really installs palworld there.
+    /// needs a disposable ubuntu host, the scripts assume user `ubuntu`:
+    /// `PSM_TEST_SSH_HOST=1.2.3.4 PSM_TEST_SSH_KEY=~/.ssh/id_ed25519 cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn start_save_stop_over_ssh() {
+        let host = std::env::var("PSM_TEST_SSH_HOST").expect("PSM_TEST_SSH_HOST");
+        let key = std::env::var("PSM_TEST_SSH_KEY").expect("PSM_TEST_SSH_KEY");
+        let csp = format!(
+            r#"
+  - name: mock
+    mock:
+      regions: {{ r1: [r1-a] }}
+      ip: {host}
+"#
+        );
+        let fixture = Fixture::new(&csp, &stopped_server(), Some(("ubuntu", &key)));
+        let handler = &fixture.handler;
+
+        handler
+            .start_or_clean_up(SERVER, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(fixture.server().status, Status::Running);
+
+        handler.save_server(SERVER, None).await.unwrap();
+        handler.stop_server(SERVER, None).await.unwrap();
+        let server = fixture.server();
+        assert_eq!(server.status, Status::Stopped);
+        let save = server.save.expect("save recorded on stop");
+        assert!(handler.local_storage.has_save(SERVER, &save).await.unwrap());
+    }
+}
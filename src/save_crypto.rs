@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+use serde::Deserialize;
+
+/// archives start with it, followed by key id length, key id and nonce prefix
+pub const MAGIC: &[u8; 8] = b"PSMENC01";
+/// aes-gcm tag appended to every sealed chunk
+pub const TAG_SIZE: usize = 16;
+pub const PREFIX_SIZE: usize = 7;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct EncryptionConfig {
+    /// key id of new archives, rotate by adding a key and pointing it here
+    pub current: String,
+    /// key id to hex encoded 32 bytes key, keep old ones to read older archives
+    pub keys: HashMap<String, String>,
+}
+
+pub struct SaveCipher {
+    current: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+// keys never show up in logs
+impl std::fmt::Debug for SaveCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SaveCipher")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl SaveCipher {
+    pub fn new(config: &EncryptionConfig) -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        for (id, key) in &config.keys {
+            anyhow::ensure!(
+                id.len() <= u8::MAX as usize,
+                "encryption key id {id} too long"
+            );
+            let key = hex::decode(key)?;
+            anyhow::ensure!(key.len() == 32, "encryption key {id} is not 32 bytes");
+            keys.insert(
+                id.clone(),
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            );
+        }
+        anyhow::ensure!(
+            keys.contains_key(&config.current),
+            "current encryption key {} not found",
+            config.current
+        );
+        Ok(Self {
+            current: config.current.clone(),
+            keys,
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    /// start a new archive with the current key, return its header
+    pub fn encryptor(&self) -> (Vec<u8>, Encryptor) {
+        let prefix = rand::random::<[u8; PREFIX_SIZE]>();
+        let mut header = MAGIC.to_vec();
+        header.push(self.current.len() as u8);
+        header.extend_from_slice(self.current.as_bytes());
+        header.extend_from_slice(&prefix);
+        let encryptor = Encryptor(Chunks {
+            cipher: self.keys[&self.current].clone(),
+            prefix,
+            counter: 0,
+            finished: false,
+        });
+        (header, encryptor)
+    }
+
+    /// continue an archive whose header after `MAGIC` was `key_id` and `prefix`
+    pub fn decryptor(&self, key_id: &str, prefix: &[u8]) -> anyhow::Result<Decryptor> {
+        let cipher = self
+            .keys
+            .get(key_id)
+            .ok_or(anyhow::anyhow!("encryption key {key_id} not found"))?;
+        Ok(Decryptor(Chunks {
+            cipher: cipher.clone(),
+            prefix: prefix.try_into()?,
+            counter: 0,
+            finished: false,
+        }))
+    }
+}
+
+/// aes-gcm over numbered chunks, the nonce is prefix + counter + last flag,
+/// so chunks can't be reordered and a truncated archive never opens
+struct Chunks {
+    cipher: Aes256Gcm,
+    prefix: [u8; PREFIX_SIZE],
+    counter: u32,
+    finished: bool,
+}
+
+impl Chunks {
+    fn next_nonce(&mut self, last: bool) -> anyhow::Result<[u8; 12]> {
+        anyhow::ensure!(!self.finished, "chunk after the last one");
+        let mut nonce = [0; 12];
+        nonce[..PREFIX_SIZE].copy_from_slice(&self.prefix);
+        nonce[PREFIX_SIZE..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(anyhow::anyhow!("too many chunks"))?;
+        self.finished = last;
+        Ok(nonce)
+    }
+}
+
+pub struct Encryptor(Chunks);
+
+impl Encryptor {
+    pub fn seal(&mut self, chunk: &[u8], last: bool) -> anyhow::Result<Vec<u8>> {
+        let nonce = self.0.next_nonce(last)?;
+        self.0
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| anyhow::anyhow!("encrypt save failed"))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.0.finished
+    }
+}
+
+pub struct Decryptor(Chunks);
+
+impl Decryptor {
+    pub fn open(&mut self, chunk: &[u8], last: bool) -> anyhow::Result<Vec<u8>> {
+        let nonce = self.0.next_nonce(last)?;
+        self.0
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| anyhow::anyhow!("decrypt save failed, wrong key or corrupted archive"))
+    }
+}
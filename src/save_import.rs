@@ -0,0 +1,538 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use tokio::process::Command;
+use tracing::info;
+
+/// co-op host player save, dedicated servers give the host a new guid
+const HOST_PLAYER: &str = "00000000000000000000000000000001";
+
+/// uncompressed len, compressed len, magic and compression of a `.sav`
+const SAV_HEADER: usize = 12;
+const SAV_MAGIC: &[u8; 3] = b"PlZ";
+/// zlib compressed once, or twice
+const SAV_ZLIB: u8 = 0x31;
+const SAV_ZLIB_TWICE: u8 = 0x32;
+
+/// what a guid property value follows in a GVAS: struct type `Guid`, an empty struct guid
+/// and no property guid
+const GUID_VALUE: &[u8] = b"\x05\0\0\0Guid\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// save archive normalized in a work dir, removed on drop
+pub struct NormalizedSave {
+    dir: PathBuf,
+    pub archive: PathBuf,
+}
+
+impl Drop for NormalizedSave {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.dir).unwrap_or_default();
+    }
+}
+
+/// turn a zip or tar of a local game `Saved` dir (or of a single world dir) into the
+/// `Saved.<ts>.tar.gz` layout `restore_save.sh` expects.
+///
+/// `host_guid` moves the co-op host to the guid a dedicated server gave them: the host
+/// player save is renamed and its guid properties, and those in Level.sav, are rewritten.
+/// guild membership is kept in raw data that is left as it is, the host rejoins the guild
+pub async fn normalize_save(
+    archive: &Path,
+    host_guid: Option<&str>,
+) -> anyhow::Result<NormalizedSave> {
+    normalize_save_in(archive, host_guid, &std::env::temp_dir()).await
+}
+
+/// `normalize_save` with its work dir under `root`
+async fn normalize_save_in(
+    archive: &Path,
+    host_guid: Option<&str>,
+    root: &Path,
+) -> anyhow::Result<NormalizedSave> {
+    let host_guid = host_guid.map(guid_bytes).transpose()?;
+    let time = chrono::Local::now().format("%Y%m%d%H%M%S");
+    let dir = root.join(format!("psm-import-{time}-{:08x}", rand::random::<u32>()));
+    // never reuse a dir, leftovers would mix into the archive. it is only ours once created
+    std::fs::create_dir(&dir)
+        .map_err(|e| anyhow::anyhow!("create work dir {}: {e}", dir.display()))?;
+    let save = NormalizedSave {
+        archive: dir.join(format!("Saved.{time}.tar.gz")),
+        dir,
+    };
+    let extracted = save.dir.join("extracted");
+    let staged = save.dir.join("staged");
+    std::fs::create_dir(&extracted)?;
+
+    let is_zip = archive
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("zip"));
+    if is_zip {
+        run(Command::new("unzip")
+            .arg("-q")
+            .arg(archive)
+            .arg("-d")
+            .arg(&extracted))
+        .await?;
+    } else {
+        run(Command::new("tar")
+            .arg("-xf")
+            .arg(archive)
+            .arg("-C")
+            .arg(&extracted))
+        .await?;
+    }
+
+    let mut levels = vec![];
+    find_files(&extracted, "Level.sav", &mut levels)?;
+    // the game keeps its own world backups under `<world>/backup/`
+    levels.retain(|level| !level.components().any(|c| c.as_os_str() == "backup"));
+    let level = match levels.as_slice() {
+        [level] => level,
+        [] => anyhow::bail!("no Level.sav in archive"),
+        _ => anyhow::bail!("more than one world in archive, keep only one"),
+    };
+    let world = level.parent().expect("file has a parent");
+    anyhow::ensure!(
+        world.join("Players").is_dir(),
+        "no Players dir next to Level.sav"
+    );
+    let world_id = world
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(anyhow::anyhow!("invalid world dir name"))?;
+
+    // Saved/SaveGames/0/<world>/Level.sav
+    let saved = staged.join("Saved");
+    let target = saved.join("SaveGames").join("0").join(world_id);
+    copy_dir(world, &target)?;
+    let config = world
+        .parent()
+        .and_then(Path::parent)
+        .and_then(Path::parent)
+        .map(|saved| saved.join("Config"))
+        // a single world dir has no `Config`, never take one from above the archive
+        .filter(|config| config.starts_with(&extracted) && config.is_dir());
+    if let Some(config) = config {
+        copy_dir(&config, &saved.join("Config"))?;
+    }
+    // the dedicated server loads the world named here, a game client only has its own platform's
+    let settings = saved.join("Config").join("LinuxServer");
+    if !settings.join("GameUserSettings.ini").is_file() {
+        std::fs::create_dir_all(&settings)?;
+        std::fs::write(
+            settings.join("GameUserSettings.ini"),
+            format!("[/Script/Pal.PalGameLocalSettings]\nDedicatedServerName={world_id}\n"),
+        )?;
+    }
+
+    if let Some(guid) = host_guid {
+        move_host(&target, &guid)?;
+    }
+
+    run(Command::new("tar")
+        .arg("-czf")
+        .arg(&save.archive)
+        .arg("-C")
+        .arg(&staged)
+        .arg("Saved"))
+    .await?;
+    info!(
+        "normalized {} to {}",
+        archive.display(),
+        save.archive.display()
+    );
+    Ok(save)
+}
+
+/// a player guid as in its save name, in the byte order of a GVAS
+fn guid_bytes(guid: &str) -> anyhow::Result<[u8; 16]> {
+    anyhow::ensure!(
+        guid.len() == 32 && guid.chars().all(|c| c.is_ascii_hexdigit()),
+        "host guid should be 32 hex chars"
+    );
+    let mut bytes = [0; 16];
+    for (i, word) in bytes.chunks_mut(4).enumerate() {
+        let value = u32::from_str_radix(&guid[i * 8..i * 8 + 8], 16)?;
+        word.copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(bytes)
+}
+
+/// rename the host player save of `world` to `guid` and point the world at it
+fn move_host(world: &Path, guid: &[u8; 16]) -> anyhow::Result<()> {
+    let host = guid_bytes(HOST_PLAYER)?;
+    let players = world.join("Players");
+    let old = players.join(format!("{HOST_PLAYER}.sav"));
+    anyhow::ensure!(old.is_file(), "no host player save in archive");
+    let name: String = guid
+        .chunks(4)
+        .map(|word| format!("{:08X}", u32::from_le_bytes(word.try_into().unwrap())))
+        .collect();
+    let new = players.join(format!("{name}.sav"));
+    anyhow::ensure!(!new.exists(), "{name} already has a player save");
+
+    let (compression, mut player) = read_sav(&old)?;
+    anyhow::ensure!(
+        replace_guid_values(&mut player, &host, guid) > 0,
+        "host player save has no player guid"
+    );
+    let level = world.join("Level.sav");
+    let (level_compression, mut gvas) = read_sav(&level)?;
+    let moved = replace_guid_values(&mut gvas, &host, guid);
+    anyhow::ensure!(moved > 0, "Level.sav has no reference to the host");
+    write_sav(&level, level_compression, &gvas)?;
+    write_sav(&new, compression, &player)?;
+    std::fs::remove_file(old)?;
+    info!("moved host to {name}, {moved} references in Level.sav");
+    Ok(())
+}
+
+/// set every guid property of `gvas` holding `from` to `to`, returns how many were set
+fn replace_guid_values(gvas: &mut [u8], from: &[u8; 16], to: &[u8; 16]) -> usize {
+    let mut count = 0;
+    let mut at = 0;
+    while let Some(found) = gvas[at..]
+        .windows(GUID_VALUE.len())
+        .position(|w| w == GUID_VALUE)
+    {
+        let value = at + found + GUID_VALUE.len();
+        if gvas.get(value..value + 16) == Some(from.as_slice()) {
+            gvas[value..value + 16].copy_from_slice(to);
+            count += 1;
+        }
+        at = value;
+    }
+    count
+}
+
+/// the compression and GVAS of a `.sav`
+fn read_sav(path: &Path) -> anyhow::Result<(u8, Vec<u8>)> {
+    let sav = std::fs::read(path)?;
+    anyhow::ensure!(
+        sav.len() > SAV_HEADER && &sav[8..11] == SAV_MAGIC,
+        "{} is not a zlib compressed save",
+        path.display()
+    );
+    let len = u32::from_le_bytes(sav[0..4].try_into()?) as usize;
+    let compression = sav[11];
+    let mut gvas = inflate(&sav[SAV_HEADER..])?;
+    match compression {
+        SAV_ZLIB => {}
+        SAV_ZLIB_TWICE => gvas = inflate(&gvas)?,
+        _ => anyhow::bail!(
+            "{} has unknown compression {compression:#x}",
+            path.display()
+        ),
+    }
+    anyhow::ensure!(gvas.len() == len, "{} is truncated", path.display());
+    Ok((compression, gvas))
+}
+
+fn write_sav(path: &Path, compression: u8, gvas: &[u8]) -> anyhow::Result<()> {
+    let once = deflate(gvas)?;
+    let mut sav = Vec::with_capacity(SAV_HEADER + once.len());
+    sav.extend_from_slice(&u32::try_from(gvas.len())?.to_le_bytes());
+    sav.extend_from_slice(&u32::try_from(once.len())?.to_le_bytes());
+    sav.extend_from_slice(SAV_MAGIC);
+    sav.push(compression);
+    match compression {
+        SAV_ZLIB_TWICE => sav.extend_from_slice(&deflate(&once)?),
+        _ => sav.extend_from_slice(&once),
+    }
+    std::fs::write(path, sav)?;
+    Ok(())
+}
+
+fn inflate(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    ZlibDecoder::new(data).read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fn deflate(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+async fn run(command: &mut Command) -> anyhow::Result<()> {
+    let output = command.output().await?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command.as_std().get_program(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+fn find_files(dir: &Path, name: &str, found: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, name, found)?;
+        } else if path.file_name().is_some_and(|n| n == name) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().expect("entry has a name"));
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            std::fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const WORLD: &str = "0123456789ABCDEF0123456789ABCDEF";
+    const GUID: &str = "abcdefabcdefabcdefabcdefabcdef12";
+
+    /// a tar of `files` (relative paths, created with some content) in a temp dir
+    struct Source {
+        _dir: TempDir,
+        archive: PathBuf,
+    }
+
+    fn tar_of(files: &[String]) -> Source {
+        let files: Vec<_> = files
+            .iter()
+            .map(|file| (file.clone(), file.as_bytes().to_vec()))
+            .collect();
+        tar_with(&files)
+    }
+
+    /// a tar of `files` with their content
+    fn tar_with(files: &[(String, Vec<u8>)]) -> Source {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("src");
+        for (file, content) in files {
+            let path = src.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        std::fs::create_dir_all(&src).unwrap();
+        let archive = dir.path().join("save.tar");
+        let status = std::process::Command::new("tar")
+            .arg("-cf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&src)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Source { _dir: dir, archive }
+    }
+
+    /// files of a game client `Saved` dir with one world
+    fn client_saved(world: &str) -> Vec<String> {
+        let world_dir = format!("Saved/SaveGames/76561198000000000/{world}");
+        vec![
+            format!("{world_dir}/Level.sav"),
+            format!("{world_dir}/LevelMeta.sav"),
+            format!("{world_dir}/Players/{HOST_PLAYER}.sav"),
+            format!("{world_dir}/backup/world/2024.01.01-00.00.00/Level.sav"),
+            "Saved/Config/WindowsNoEditor/GameUserSettings.ini".into(),
+        ]
+    }
+
+    /// extract the normalized archive next to it
+    fn unpack(save: &NormalizedSave) -> PathBuf {
+        let out = save.dir.join("check");
+        std::fs::create_dir(&out).unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("-xzf")
+            .arg(&save.archive)
+            .arg("-C")
+            .arg(&out)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        out.join("Saved")
+    }
+
+    async fn import_err(files: &[String], host_guid: Option<&str>) -> String {
+        let source = tar_of(files);
+        match normalize_save(&source.archive, host_guid).await {
+            Ok(_) => panic!("{files:?} imported"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_saved_dir_is_normalized() {
+        let source = tar_of(&client_saved(WORLD));
+        let save = normalize_save(&source.archive, None).await.unwrap();
+        let saved = unpack(&save);
+
+        let world = saved.join("SaveGames/0").join(WORLD);
+        assert!(world.join("Level.sav").is_file());
+        assert!(world
+            .join("Players")
+            .join(format!("{HOST_PLAYER}.sav"))
+            .is_file());
+        // the game's own backups are copied along but never taken as another world
+        assert!(world.join("backup").is_dir());
+        assert!(saved
+            .join("Config/WindowsNoEditor/GameUserSettings.ini")
+            .is_file());
+        let settings =
+            std::fs::read_to_string(saved.join("Config/LinuxServer/GameUserSettings.ini")).unwrap();
+        assert!(settings.contains(&format!("DedicatedServerName={WORLD}")));
+    }
+
+    #[tokio::test]
+    async fn existing_server_settings_are_kept() {
+        let mut files = client_saved(WORLD);
+        files.push("Saved/Config/LinuxServer/GameUserSettings.ini".into());
+        let source = tar_of(&files);
+        let save = normalize_save(&source.archive, None).await.unwrap();
+
+        let settings =
+            std::fs::read_to_string(unpack(&save).join("Config/LinuxServer/GameUserSettings.ini"))
+                .unwrap();
+        assert_eq!(settings, "Saved/Config/LinuxServer/GameUserSettings.ini");
+    }
+
+    #[tokio::test]
+    async fn single_world_dir_is_normalized() {
+        let source = tar_of(&[
+            format!("{WORLD}/Level.sav"),
+            format!("{WORLD}/Players/{HOST_PLAYER}.sav"),
+        ]);
+        let save = normalize_save(&source.archive, None).await.unwrap();
+        let saved = unpack(&save);
+
+        assert!(saved
+            .join("SaveGames/0")
+            .join(WORLD)
+            .join("Level.sav")
+            .is_file());
+        assert!(saved
+            .join("Config/LinuxServer/GameUserSettings.ini")
+            .is_file());
+
+        // a `Config` three levels above the world dir, but outside of the archive
+        let root = TempDir::new().unwrap();
+        let stray = root.path().join("Config/LinuxServer");
+        std::fs::create_dir_all(&stray).unwrap();
+        std::fs::write(stray.join("GameUserSettings.ini"), "stray").unwrap();
+        std::fs::create_dir(root.path().join("Config/Stray")).unwrap();
+        let save = normalize_save_in(&source.archive, None, root.path())
+            .await
+            .unwrap();
+        let saved = unpack(&save);
+
+        assert!(!saved.join("Config/Stray").exists());
+        let settings =
+            std::fs::read_to_string(saved.join("Config/LinuxServer/GameUserSettings.ini")).unwrap();
+        assert!(settings.contains(&format!("DedicatedServerName={WORLD}")));
+    }
+
+    #[tokio::test]
+    async fn invalid_saves_are_rejected() {
+        let no_level = vec![format!("{WORLD}/Players/{HOST_PLAYER}.sav")];
+        assert!(import_err(&no_level, None).await.contains("no Level.sav"));
+
+        let mut two_worlds = client_saved(WORLD);
+        two_worlds.extend(client_saved("FEDCBA9876543210FEDCBA9876543210"));
+        assert!(import_err(&two_worlds, None)
+            .await
+            .contains("more than one world"));
+
+        let no_players = vec![format!("{WORLD}/Level.sav")];
+        assert!(import_err(&no_players, None).await.contains("no Players"));
+
+        let no_host = vec![
+            format!("{WORLD}/Level.sav"),
+            format!("{WORLD}/Players/{GUID}.sav"),
+        ];
+        assert!(import_err(&no_host, Some(GUID))
+            .await
+            .contains("no host player save"));
+        assert!(import_err(&client_saved(WORLD), Some("not-a-guid"))
+            .await
+            .contains("32 hex chars"));
+        // placeholder files are no zlib saves
+        assert!(import_err(&client_saved(WORLD), Some(GUID))
+            .await
+            .contains("not a zlib compressed save"));
+    }
+
+    /// a GVAS with `guid` as a guid property, and as raw data that is no property
+    fn gvas_of(guid: &str) -> Vec<u8> {
+        let guid = guid_bytes(guid).unwrap();
+        let mut gvas = b"GVAS\x0a\0\0\0PlayerUId\0".to_vec();
+        gvas.extend_from_slice(GUID_VALUE);
+        gvas.extend_from_slice(&guid);
+        gvas.extend_from_slice(b"\x05\0\0\0None\0");
+        gvas.extend_from_slice(&guid);
+        gvas
+    }
+
+    fn sav_of(compression: u8, gvas: &[u8]) -> Vec<u8> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("save.sav");
+        write_sav(&path, compression, gvas).unwrap();
+        std::fs::read(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn host_guid_moves_host_player() {
+        let world_dir = format!("Saved/SaveGames/76561198000000000/{WORLD}");
+        let source = tar_with(&[
+            (
+                format!("{world_dir}/Level.sav"),
+                sav_of(SAV_ZLIB_TWICE, &gvas_of(HOST_PLAYER)),
+            ),
+            (
+                format!("{world_dir}/Players/{HOST_PLAYER}.sav"),
+                sav_of(SAV_ZLIB, &gvas_of(HOST_PLAYER)),
+            ),
+        ]);
+        let save = normalize_save(&source.archive, Some(GUID)).await.unwrap();
+        let world = unpack(&save).join("SaveGames/0").join(WORLD);
+
+        let players = world.join("Players");
+        assert!(!players.join(format!("{HOST_PLAYER}.sav")).exists());
+        let player = players.join(format!("{}.sav", GUID.to_uppercase()));
+        let level = world.join("Level.sav");
+        for (path, compression) in [(player, SAV_ZLIB), (level, SAV_ZLIB_TWICE)] {
+            let (read, gvas) = read_sav(&path).unwrap();
+            assert_eq!(read, compression);
+            // only the property moves, raw data that happens to match is kept
+            let mut expected = gvas_of(GUID);
+            let raw = expected.len() - 16;
+            expected[raw..].copy_from_slice(&guid_bytes(HOST_PLAYER).unwrap());
+            assert_eq!(gvas, expected);
+        }
+    }
+
+    #[test]
+    fn guid_bytes_follow_save_names() {
+        let bytes = guid_bytes(HOST_PLAYER).unwrap();
+        assert_eq!(bytes[12..], [1, 0, 0, 0]);
+        assert!(bytes[..12].iter().all(|&b| b == 0));
+        assert_eq!(
+            guid_bytes("0123456789ABCDEF0123456789abcdef").unwrap()[..4],
+            [0x67, 0x45, 0x23, 0x01]
+        );
+    }
+}
@@ -3,6 +3,8 @@ use std::{fmt::Display, path::Path};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::config::{BackupConfig, PlacementConfig, QuietHours};
+
 pub struct ServerManager {
     path: String,
     servers: Vec<Server>,
@@ -14,6 +16,8 @@ pub enum ServerManagerError {
     ServerNotFound,
     #[error("Server status not match, current status: {0}")]
     ServerStatusNotMatch(Status),
+    #[error("Server {0} has unknown regions: {1:?}")]
+    UnknownRegion(String, Vec<String>),
     #[error("Server IO error: {0}")]
     ServerIO(#[from] std::io::Error),
     #[error("Server serde error: {0}")]
@@ -21,6 +25,24 @@ pub enum ServerManagerError {
 }
 
 type ServerManagerResult<T> = Result<T, ServerManagerError>;
+
+/// lock `<status file>.lock` while the returned file lives, so only one process
+/// (the manager, or a cli import) writes the status file
+pub fn lock_status_file(path: &Path) -> anyhow::Result<std::fs::File> {
+    let lock_path = format!("{}.lock", path.display());
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+            "{lock_path} is held by a running manager, stop it first"
+        )),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
 impl ServerManager {
     pub fn new(path: &Path) -> Self {
         let path = path.to_str().unwrap().to_string();
@@ -39,11 +61,92 @@ impl ServerManager {
         Ok(server.instance_type.clone())
     }
 
+    /// server placement, falling back to the global one
+    pub fn get_placement(
+        &self,
+        server: &str,
+        global: &PlacementConfig,
+    ) -> ServerManagerResult<PlacementConfig> {
+        let server = self.find_server_or_err(server)?;
+        Ok(PlacementConfig {
+            regions: server.regions.clone().unwrap_or(global.regions.clone()),
+            excluded_zones: server
+                .excluded_zones
+                .clone()
+                .unwrap_or(global.excluded_zones.clone()),
+            max_price: server.max_price.or(global.max_price),
+        })
+    }
+
+    /// server backup schedule, falling back to the global one
+    pub fn get_backup_config(
+        &self,
+        server: &str,
+        global: &BackupConfig,
+    ) -> ServerManagerResult<BackupConfig> {
+        let server = self.find_server_or_err(server)?;
+        Ok(BackupConfig {
+            interval: server.backup_interval.or(global.interval),
+            quiet_hours: server.quiet_hours.or(global.quiet_hours),
+        })
+    }
+
+    pub fn check_regions(&self, is_known_region: impl Fn(&str) -> bool) -> ServerManagerResult<()> {
+        for server in &self.servers {
+            let unknown: Vec<_> = server
+                .regions
+                .iter()
+                .flatten()
+                .filter(|r| !is_known_region(r))
+                .cloned()
+                .collect();
+            if !unknown.is_empty() {
+                return Err(ServerManagerError::UnknownRegion(
+                    server.name.clone(),
+                    unknown,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn running_servers(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .filter(|s| s.status == Status::Running)
+            .map(|s| s.name.clone())
+            .collect()
+    }
+
+    /// (provider, region, instance_id) if server is running
+    pub fn get_running_instance(
+        &self,
+        server: &str,
+    ) -> ServerManagerResult<Option<(Option<String>, String, String)>> {
+        let server = self.find_server_or_err(server)?;
+        if server.status != Status::Running {
+            return Ok(None);
+        }
+        Ok(server
+            .region
+            .clone()
+            .zip(server.instance_id.clone())
+            .map(|(region, id)| (server.provider.clone(), region, id)))
+    }
+
     pub fn get_save_name(&self, server: &str) -> ServerManagerResult<Option<String>> {
         let server = self.find_server_or_err(server)?;
         Ok(server.save.clone())
     }
 
+    /// (server, save) of every server
+    pub fn save_owners(&self) -> Vec<(String, Option<String>)> {
+        self.servers
+            .iter()
+            .map(|s| (s.name.clone(), s.save.clone()))
+            .collect()
+    }
+
     pub fn get_server_ip(&self, server: &str) -> ServerManagerResult<Option<String>> {
         let server = self.find_server_or_err(server)?;
         Ok(server
@@ -67,13 +170,46 @@ impl ServerManager {
         Ok(())
     }
 
+    /// Stopped -> Creating, so only one start of a stopped server goes on
     pub fn create_server(&mut self, server: &str) -> ServerManagerResult<()> {
         let server = self.find_server_or_err_mut(server)?;
+        if server.status != Status::Stopped {
+            return Err(ServerManagerError::ServerStatusNotMatch(
+                server.status.clone(),
+            ));
+        }
         server.status = Status::Creating;
         self.update()?;
         Ok(())
     }
 
+    /// remember the instance as soon as it is run, before waiting for it to boot, so a failed
+    /// or cancelled start can terminate it and a crashed one leaves it in the status file
+    pub fn record_instance(
+        &mut self,
+        server: &str,
+        provider: &str,
+        region: &str,
+        instance_id: &str,
+    ) -> ServerManagerResult<()> {
+        let server = self.find_server_or_err_mut(server)?;
+        server.provider = Some(provider.to_owned());
+        server.region = Some(region.to_owned());
+        server.instance_id = Some(instance_id.to_owned());
+        self.update()?;
+        Ok(())
+    }
+
+    /// drop a recorded instance already terminated, the start goes on with another one
+    pub fn forget_instance(&mut self, server: &str) -> ServerManagerResult<()> {
+        let server = self.find_server_or_err_mut(server)?;
+        server.provider = None;
+        server.region = None;
+        server.instance_id = None;
+        self.update()?;
+        Ok(())
+    }
+
     pub fn finish_creating_server(
         &mut self,
         server: &str,
@@ -90,39 +226,61 @@ impl ServerManager {
         Ok(())
     }
 
+    /// return (provider, instance_id, region) to clean up
     pub fn failed_create_server(
         &mut self,
         server: &str,
-    ) -> ServerManagerResult<(Option<String>, Option<String>)> {
+    ) -> ServerManagerResult<(Option<String>, Option<String>, Option<String>)> {
         let server = self.find_server_or_err_mut(server)?;
         server.status = Status::Stopped;
-        let (region, id) = (server.region.clone(), server.instance_id.clone());
+        let (provider, region, id) = (
+            server.provider.take(),
+            server.region.take(),
+            server.instance_id.take(),
+        );
         self.update()?;
-        Ok((id, region))
+        Ok((provider, id, region))
     }
 
+    /// Stopping -> Running, for a stop whose instance could not be terminated
     pub fn failed_stop_server(&mut self, server: &str) -> ServerManagerResult<()> {
         let server = self.find_server_or_err_mut(server)?;
+        if server.status != Status::Stopping {
+            return Err(ServerManagerError::ServerStatusNotMatch(
+                server.status.clone(),
+            ));
+        }
         server.status = Status::Running;
         self.update()?;
         Ok(())
     }
 
-    pub fn stop_server(&mut self, server: &str) -> ServerManagerResult<(String, String)> {
+    /// Running -> Stopping, return (provider, region, instance_id) to terminate
+    pub fn stop_server(
+        &mut self,
+        server: &str,
+    ) -> ServerManagerResult<(Option<String>, String, String)> {
         let server = self.find_server_or_err_mut(server)?;
-        let (region, id) = (
+        if server.status != Status::Running {
+            return Err(ServerManagerError::ServerStatusNotMatch(
+                server.status.clone(),
+            ));
+        }
+        let (provider, region, id) = (
+            server.provider.clone(),
             server.region.clone().unwrap(),
             server.instance_id.clone().unwrap(),
         );
         server.status = Status::Stopping;
         self.update()?;
-        Ok((region, id))
+        Ok((provider, region, id))
     }
 
     pub fn finish_stopping_server(&mut self, server: &str) -> ServerManagerResult<()> {
         let server = self.find_server_or_err_mut(server)?;
         server.status = Status::Stopped;
         server.ip_port = None;
+        server.provider = None;
         server.region = None;
         server.instance_id = None;
         self.update()?;
@@ -161,8 +319,17 @@ pub struct Server {
     pub name: String,
     pub status: Status,
     pub instance_type: String,
+    /// candidate regions overriding the global placement
+    pub regions: Option<Vec<String>>,
+    pub excluded_zones: Option<Vec<String>>,
+    pub max_price: Option<f64>,
+    /// minutes between periodic backups, overriding the global one
+    pub backup_interval: Option<u64>,
+    pub quiet_hours: Option<QuietHours>,
     pub save: Option<String>,
     pub ip_port: Option<String>,
+    /// provider name the instance is running on
+    pub provider: Option<String>,
     pub region: Option<String>,
     pub instance_id: Option<String>,
 }
@@ -1,6 +1,15 @@
-use std::{io::Read, net::TcpStream, path::Path};
+use std::{
+    io::Read,
+    net::{TcpStream, ToSocketAddrs},
+    path::Path,
+    time::Duration,
+};
 
-use tracing::debug;
+use async_trait::async_trait;
+use opendal::{services::Sftp, Operator};
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::{debug, info};
 
 use crate::config::SshConfig;
 
@@ -8,14 +17,102 @@ use crate::config::SshConfig;
 pub enum Script {
     /// install_server.sh
     InstallServer,
-    /// restore_save.sh
-    RestoreSave,
+    /// restore_save.sh with the server, archive name and its sha256
+    RestoreSave(String, String, String),
     /// start_server.sh
     StartServer,
-    /// backup_save.sh
-    BackupSave,
+    /// backup_save.sh with the server name
+    BackupSave(String),
 }
 
+impl Script {
+    /// how long a run may take before its process group is killed
+    fn timeout(&self) -> Duration {
+        match self {
+            Script::InstallServer => Duration::from_secs(40 * 60),
+            Script::RestoreSave(..) | Script::BackupSave(_) => Duration::from_secs(10 * 60),
+            Script::StartServer => Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// becomes true when the operation running a script is cancelled
+pub type Cancel = watch::Receiver<bool>;
+
+/// a cancel that never fires, for operations that can't be cancelled
+pub fn no_cancel() -> Cancel {
+    // the receiver keeps the last value after the sender is gone
+    watch::channel(false).1
+}
+
+/// each script run gets `<RUNS_DIR>/<run id>/{pid,stdout,stderr,exit}` on the instance,
+/// pid is the leader of the run's process group and carries `PSM_RUN=<run id>` in its env
+const RUNS_DIR: &str = "/tmp/psm-runs";
+
+/// machine readable json a script may print as its last stdout line, fields depend on the script
+#[derive(Debug, Deserialize, Default)]
+pub struct ScriptResult {
+    /// backup_save.sh: archive name
+    pub save: Option<String>,
+    /// backup_save.sh: sha256 of the archive
+    pub checksum: Option<String>,
+}
+
+/// notable stdout lines of a running script
+#[derive(Debug)]
+pub enum ScriptProgress {
+    /// steamcmd download or validate state with its percentage
+    Percent(String, f32),
+    /// retries and other lines worth a message each
+    Notice(String),
+}
+
+impl ScriptProgress {
+    fn parse(line: &str) -> Option<Self> {
+        // Update state (0x61) downloading, progress: 45.23 (1234 / 5678)
+        if let Some((state, progress)) = line.split_once(", progress: ") {
+            let stage = state.rsplit(')').next()?.trim();
+            let percent = progress.split_whitespace().next()?.parse().ok()?;
+            return Some(Self::Percent(stage.to_string(), percent));
+        }
+        (line.contains("retrying") || line.contains("Max retries"))
+            .then(|| Self::Notice(line.trim().to_string()))
+    }
+}
+
+/// a script run that exited 0
+#[derive(Debug)]
+pub struct ScriptOutcome {
+    pub run_id: String,
+    pub last_line: String,
+    pub result: Option<ScriptResult>,
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// each status poll of a script run, so a stalled connection can't outlive the script timeout
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// everything psm does on an instance
+#[async_trait]
+pub trait RemoteShell: Send + Sync {
+    /// run a short command and return its stdout
+    async fn exec(&self, ip: &str, command: &str) -> anyhow::Result<String>;
+
+    /// run a script in the background and wait for it, fails if it exits non zero
+    async fn run(
+        &self,
+        ip: &str,
+        script: Script,
+        progress: &(dyn Fn(ScriptProgress) + Send + Sync),
+        cancel: &Cancel,
+    ) -> anyhow::Result<ScriptOutcome>;
+
+    /// files of the instance under `root`
+    fn files(&self, ip: &str, root: &str) -> anyhow::Result<Operator>;
+}
+
+/// runs scripts and moves files over ssh
 #[derive(Debug)]
 pub struct ShellManager {
     pub ssh_config: SshConfig,
@@ -26,56 +123,263 @@ impl ShellManager {
         Self { ssh_config }
     }
 
-    pub async fn run(&self, ip: &str, script: Script) -> anyhow::Result<String> {
+    /// connect and authenticate on the blocking pool, giving up on an unreachable or stuck host
+    async fn connect(&self, ip: &str) -> anyhow::Result<ssh2::Session> {
+        let user = self.ssh_config.user.clone();
+        let prikey_path = self.ssh_config.prikey.clone();
+        let addr = format!("{ip}:22");
+
+        tokio::task::spawn_blocking(move || {
+            let addr = addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or(anyhow::anyhow!("can't resolve {addr}"))?;
+            let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+            let mut sess = ssh2::Session::new()?;
+            sess.set_tcp_stream(tcp);
+            sess.set_timeout(HANDSHAKE_TIMEOUT.as_millis() as u32);
+            sess.handshake()?;
+            sess.userauth_pubkey_file(&user, None, Path::new(&prikey_path), None)?;
+            sess.authenticated()
+                .then(|| debug!("ssh2 authed"))
+                .ok_or(anyhow::anyhow!("ssh2 auth failed"))?;
+            // presigned save transfers may take long, curl bounds them with its own `-m`
+            // and script runs are bounded by POLL_TIMEOUT on each status poll
+            sess.set_timeout(0);
+            Ok(sess)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl RemoteShell for ShellManager {
+    async fn exec(&self, ip: &str, command: &str) -> anyhow::Result<String> {
+        let sess = self.connect(ip).await?;
+        exec_on(&sess, command).await
+    }
+
+    async fn run(
+        &self,
+        ip: &str,
+        script: Script,
+        progress: &(dyn Fn(ScriptProgress) + Send + Sync),
+        cancel: &Cancel,
+    ) -> anyhow::Result<ScriptOutcome> {
+        let timeout = script.timeout();
         let user = &self.ssh_config.user;
-        let prikey_path = &self.ssh_config.prikey;
-
-        let tcp = TcpStream::connect(format!("{ip}:22"))?;
-        let mut sess = ssh2::Session::new()?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-        sess.userauth_pubkey_file(user, None, Path::new(prikey_path), None)?;
-        sess.authenticated()
-            .then(|| debug!("ssh2 authed"))
-            .ok_or(anyhow::anyhow!("ssh2 auth failed"))?;
-
-        let script_name = match script {
-            Script::InstallServer => "install_server.sh",
-            Script::RestoreSave => "restore_save.sh",
-            Script::StartServer => "start_server.sh",
-            Script::BackupSave => "backup_save.sh",
+        let sess = self.connect(ip).await?;
+        sess.set_timeout(POLL_TIMEOUT.as_millis() as u32);
+
+        let (script_name, args) = match &script {
+            Script::InstallServer => ("install_server.sh", String::new()),
+            Script::RestoreSave(server, save_name, checksum) => (
+                "restore_save.sh",
+                format!("{server} {save_name} {checksum}"),
+            ),
+            Script::StartServer => ("start_server.sh", String::new()),
+            Script::BackupSave(server) => ("backup_save.sh", server.clone()),
         };
 
-        let mut channel = sess.channel_session()?;
-        channel.exec(&format!(
-            "(sh /home/{user}/psm/scripts/{script_name} >> /tmp/shell_log.log 2>&1 &)"
-        ))?;
+        let run_id = format!(
+            "{}-{}-{:08x}",
+            script_name.trim_end_matches(".sh"),
+            chrono::Local::now().format("%Y%m%d%H%M%S"),
+            rand::random::<u32>()
+        );
+        let run_dir = format!("{RUNS_DIR}/{run_id}");
+        debug!("run {script_name} as {run_id}");
+        exec_on(
+            &sess,
+            &format!(
+                // setsid puts the script in its own process group, so it can be killed as a whole
+                "mkdir -p {run_dir} && (PSM_RUN={run_id} setsid sh -c 'echo $$ > {run_dir}/pid; sh /home/{user}/psm/scripts/{script_name} {args} > {run_dir}/stdout 2> {run_dir}/stderr; echo $? > {run_dir}/exit.tmp; mv {run_dir}/exit.tmp {run_dir}/exit' > /dev/null 2>&1 &)"
+            ),
+        )
+        .await?;
 
         const CHECK_INTERVAL: u64 = 5;
-        loop {
-            let mut channel = sess.channel_session()?;
-            channel.exec(&format!(
-                "ps -ef | grep {script_name} | grep -v grep | wc -l",
-            ))?;
-            let mut process_cnt = String::new();
-            channel.read_to_string(&mut process_cnt)?;
-            if process_cnt.trim() == "0" {
-                break;
+        let started = tokio::time::Instant::now();
+        let mut cancel = cancel.clone();
+        // bytes of stdout already looked at, only whole lines are consumed
+        let mut offset = 0;
+        let exit_code = loop {
+            // raw bytes, the tail may end inside a character still being written
+            let output = exec_raw_on(
+                &sess,
+                &format!("tail -c +{} {run_dir}/stdout 2>/dev/null", offset + 1),
+            )
+            .await?;
+            if let Some(end) = output.iter().rposition(|&b| b == b'\n') {
+                offset += end + 1;
+                String::from_utf8_lossy(&output[..end])
+                    .lines()
+                    // steamcmd redraws its progress with carriage returns
+                    .flat_map(|line| line.split('\r'))
+                    .filter_map(ScriptProgress::parse)
+                    .for_each(progress);
+            }
+
+            match run_state(&sess, &run_dir, &run_id).await? {
+                RunState::Exited(code) => break code,
+                RunState::Running => debug!(" - {run_id} running..."),
+                RunState::Gone => {
+                    anyhow::bail!("{script_name} ({run_id}) died without an exit status")
+                }
+            }
+            if started.elapsed() > timeout {
+                kill(&sess, &run_dir).await;
+                anyhow::bail!("{script_name} ({run_id}) timed out after {timeout:?}");
+            }
+            let cancelled = tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(CHECK_INTERVAL)) => false,
+                Ok(_) = cancel.wait_for(|cancelled| *cancelled) => true,
+            };
+            if cancelled {
+                kill(&sess, &run_dir).await;
+                anyhow::bail!("{script_name} ({run_id}) cancelled");
             }
-            debug!(" - running...");
-            tokio::time::sleep(tokio::time::Duration::from_secs(CHECK_INTERVAL)).await;
+        };
+
+        let last_line = exec_on(&sess, &format!("tail -n 1 {run_dir}/stdout")).await?;
+        let last_line = last_line.trim().to_string();
+        debug!(" -last line of {run_id}: {last_line}");
+        if exit_code != 0 {
+            let stderr = exec_on(&sess, &format!("tail -n 5 {run_dir}/stderr")).await?;
+            let stderr = stderr.trim();
+            anyhow::bail!(
+                "{script_name} ({run_id}) exited with {exit_code}: {}",
+                if stderr.is_empty() {
+                    &last_line
+                } else {
+                    stderr
+                }
+            );
         }
+        let result = last_line
+            .starts_with('{')
+            .then(|| serde_json::from_str(&last_line))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("invalid result of {run_id}: {e}"))?;
+        Ok(ScriptOutcome {
+            run_id,
+            last_line,
+            result,
+        })
+    }
 
-        let res = {
-            let mut channel = sess.channel_session()?;
-            channel.exec("tail -n 1 /tmp/shell_log.log")?;
-            let mut logs = String::new();
-            channel.read_to_string(&mut logs)?;
-            debug!(" -logs: {}", logs);
-            channel.close()?;
-            debug!(" -status: {}", channel.exit_status()?);
-            logs
+    fn files(&self, ip: &str, root: &str) -> anyhow::Result<Operator> {
+        let endpoint = format!("ssh://{}@{}:22", self.ssh_config.user, ip);
+        let mut sftp = Sftp::default();
+        sftp.root(root)
+            .endpoint(&endpoint)
+            .key(&self.ssh_config.prikey)
+            .user(&self.ssh_config.user)
+            .known_hosts_strategy("Accept");
+        Ok(Operator::new(sftp)?.finish())
+    }
+}
+
+enum RunState {
+    Exited(i32),
+    Running,
+    /// no exit status and its leader is gone, e.g. killed or the instance rebooted
+    Gone,
+}
+
+/// look a run up by its files and its id, never by process names
+async fn run_state(sess: &ssh2::Session, run_dir: &str, run_id: &str) -> anyhow::Result<RunState> {
+    // the pid file is written right after launch, treat a missing one as still starting
+    let output = exec_on(
+        sess,
+        &format!(
+            "if [ -f {run_dir}/exit ]; then echo exit $(cat {run_dir}/exit); \
+             elif [ ! -f {run_dir}/pid ] || grep -qzx PSM_RUN={run_id} /proc/$(cat {run_dir}/pid)/environ 2>/dev/null; then echo running; \
+             elif [ -f {run_dir}/exit ]; then echo exit $(cat {run_dir}/exit); \
+             else echo gone; fi"
+        ),
+    )
+    .await?;
+    Ok(
+        match output.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["exit", code] => RunState::Exited(code.parse()?),
+            ["running"] => RunState::Running,
+            ["gone"] => RunState::Gone,
+            _ => anyhow::bail!("unexpected state of {run_id}: {output}"),
+        },
+    )
+}
+
+/// kill the process group of a run, the script and whatever it started
+async fn kill(sess: &ssh2::Session, run_dir: &str) {
+    info!("kill run {run_dir}");
+    exec_on(sess, &format!("kill -TERM -- -$(cat {run_dir}/pid)"))
+        .await
+        .map(|_| ())
+        .unwrap_or_else(|e| debug!("kill {run_dir}: {e}"));
+}
+
+/// exec `command` in a new channel on the blocking pool, return its stdout
+async fn exec_on(sess: &ssh2::Session, command: &str) -> anyhow::Result<String> {
+    let output = exec_raw_on(sess, command).await?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// like `exec_on`, stdout is returned as is
+async fn exec_raw_on(sess: &ssh2::Session, command: &str) -> anyhow::Result<Vec<u8>> {
+    let sess = sess.clone();
+    let command = command.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut channel = sess.channel_session()?;
+        channel.exec(&command)?;
+        let mut output = vec![];
+        channel.read_to_end(&mut output)?;
+        channel.close()?;
+        debug!(" -status of `{command}`: {}", channel.exit_status()?);
+        Ok(output)
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    /// against a disposable sshd, e.g. an openssh-server container, whose
+    /// `psm/scripts/start_server.sh` is replaced by a sleep
+    #[tokio::test(flavor = "current_thread")]
+    #[ignore = "needs PSM_TEST_SSH_HOST, PSM_TEST_SSH_USER and PSM_TEST_SSH_KEY"]
+    async fn runs_overlap_on_one_thread() {
+        let env = |key| std::env::var(key).unwrap_or_else(|_| panic!("{key} is not set"));
+        let (host, user) = (env("PSM_TEST_SSH_HOST"), env("PSM_TEST_SSH_USER"));
+        let shell = ShellManager::new(SshConfig {
+            prikey: env("PSM_TEST_SSH_KEY"),
+            user: user.clone(),
+        });
+        let scripts = format!("/home/{user}/psm/scripts");
+        shell
+            .exec(
+                &host,
+                &format!("mkdir -p {scripts} && echo 'sleep 8' > {scripts}/start_server.sh"),
+            )
+            .await
+            .unwrap();
+
+        let timed_run = || async {
+            let start = Instant::now();
+            shell
+                .run(&host, Script::StartServer, &|_| (), &no_cancel())
+                .await
+                .unwrap();
+            (start, Instant::now())
         };
-        Ok(res)
+        let ((a_start, a_end), (b_start, b_end)) = tokio::join!(timed_run(), timed_run());
+
+        assert!(a_start < b_end && b_start < a_end);
+        // one run after the other would take two sleeps and two polls, at least 20s
+        let elapsed = a_end.max(b_end) - a_start.min(b_start);
+        assert!(elapsed < Duration::from_secs(15), "took {elapsed:?}");
     }
 }
@@ -0,0 +1,27 @@
+use std::{convert::Infallible, future::Future};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+
+/// serve `stand_in` on a free local port, return the endpoint
+pub fn serve<S, F>(stand_in: S, handle: fn(S, Request<Body>) -> F) -> String
+where
+    S: Clone + Send + Sync + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let stand_in = stand_in.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let handled = handle(stand_in.clone(), req);
+                async move { Ok::<_, Infallible>(handled.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let endpoint = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    endpoint
+}